@@ -4,16 +4,22 @@
 // or the MIT license <LICENSE-MIT>, at your option. You may not use
 // this file except according to those terms.
 
+use common::*;
+use futures::Future;
+use futures::finished;
 use std::fmt;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Result;
+use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::net::SocketAddrV4;
 use std::net::SocketAddrV6;
 use std::str::FromStr;
+use tokio_core::io::IoFuture;
 use tokio_dns::Endpoint;
 use tokio_dns::ToEndpoint;
+use tokio_dns;
 
 /// A domain address which is a (domain name, port) combination.
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
@@ -194,6 +200,80 @@ impl<'a> ToAddr for (&'a str) {
     }
 }
 
+/// Policy of resolving destination domain names.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Dns {
+    /// Domain names are resolved locally and the proxy receives an IP address.
+    Local,
+    /// Domain names are sent to the proxy and resolved there.
+    Remote,
+}
+
+/// Resolves a domain address using local resolver, when required by DNS
+/// policy. Other addresses are returned unchanged.
+pub fn resolve(address: Addr, dns: Dns) -> IoFuture<Addr> {
+    match dns {
+        Dns::Local => resolve_first(address, |_| true),
+        Dns::Remote => finished(address).boxed(),
+    }
+}
+
+/// Resolves a domain address to an IPv4 address using local resolver, when
+/// required by DNS policy. Other addresses are returned unchanged.
+pub fn resolve_ipv4(address: Addr, dns: Dns) -> IoFuture<Addr> {
+    match dns {
+        Dns::Local => resolve_first(address, |ip| ip.is_ipv4()),
+        Dns::Remote => finished(address).boxed(),
+    }
+}
+
+/// Resolves an address to all socket addresses using local resolver.
+pub fn resolve_all(address: &Addr) -> IoFuture<Vec<SocketAddr>> {
+    let domain = match *address {
+        Addr::V4(sa) => return finished(vec![SocketAddr::V4(sa)]).boxed(),
+        Addr::V6(sa) => return finished(vec![SocketAddr::V6(sa)]).boxed(),
+        Addr::Domain(ref domain) => domain.clone(),
+    };
+    tokio_dns::resolve(domain.domain()).map(move |ips| {
+        ips.into_iter().map(|ip| SocketAddr::new(ip, domain.port())).collect()
+    }).boxed()
+}
+
+/// Returns the host of an address for matching against rules, as a
+/// lowercase domain name without trailing dot, and as an IP address when
+/// either an IP address or a domain name holding an IP literal is given.
+pub fn match_host(address: &Addr) -> (Option<String>, Option<IpAddr>) {
+    match *address {
+        Addr::V4(ref sa) => (None, Some(IpAddr::V4(*sa.ip()))),
+        Addr::V6(ref sa) => (None, Some(IpAddr::V6(*sa.ip()))),
+        Addr::Domain(ref da) => {
+            let domain = da.domain().trim_right_matches('.').to_lowercase();
+            let ip = IpAddr::from_str(&domain).ok();
+            (Some(domain), ip)
+        }
+    }
+}
+
+/// Resolves a domain address to the first IP address accepted by a filter.
+fn resolve_first<F>(address: Addr, accept: F) -> IoFuture<Addr>
+    where F: Fn(&IpAddr) -> bool + Send + 'static
+{
+    let domain = match address {
+        Addr::Domain(domain) => domain,
+        address => return finished(address).boxed(),
+    };
+    tokio_dns::resolve(domain.domain()).and_then(move |ips| {
+        ips.into_iter().find(|ip| accept(ip)).map(|ip| {
+            match ip {
+                IpAddr::V4(ip) => Addr::V4(SocketAddrV4::new(ip, domain.port())),
+                IpAddr::V6(ip) => Addr::V6(SocketAddrV6::new(ip, domain.port(), 0, 0)),
+            }
+        }).ok_or_else(|| {
+            other(format!("proxy: No suitable address found for {}", domain.domain()))
+        })
+    }).boxed()
+}
+
 #[doc(hidden)]
 /// Converts an address to an endpoint as used by tokio-dns crate.
 ///
@@ -215,6 +295,7 @@ mod tests {
     use address::*;
     use std::net::*;
     use std::str::FromStr;
+    use tokio_core::reactor::Core;
 
     #[test]
     fn to_addr_from_socket_addr() {
@@ -267,6 +348,20 @@ mod tests {
         assert!(Addr::from_str("not an address").is_err());
     }
 
+    #[test]
+    fn resolve_ipv4_domain() {
+        let mut reactor = Core::new().unwrap();
+        let address = reactor.run(resolve_ipv4("localhost:80".to_addr().unwrap(), Dns::Local)).unwrap();
+        assert_eq!("127.0.0.1:80".to_addr().unwrap(), address);
+    }
+
+    #[test]
+    fn resolve_remote() {
+        let mut reactor = Core::new().unwrap();
+        let address = reactor.run(resolve("localhost:80".to_addr().unwrap(), Dns::Remote)).unwrap();
+        assert_eq!("localhost:80".to_addr().unwrap(), address);
+    }
+
     #[test]
     fn display() {
         assert_eq!(
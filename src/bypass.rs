@@ -0,0 +1,294 @@
+// Copyright 2016 Tomasz Miąsko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE>
+// or the MIT license <LICENSE-MIT>, at your option. You may not use
+// this file except according to those terms.
+
+use address::Addr;
+use address::match_host;
+use common::*;
+use std::env;
+use std::io::Error;
+use std::io::Result;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// Environment variables holding bypass rules, in order of precedence.
+const BYPASS_VARIABLES: [&'static str; 2] = ["NO_PROXY", "no_proxy"];
+
+/// A set of destinations that should be connected to directly, bypassing
+/// the proxy.
+///
+/// Rules are parsed from a comma-separated list in `NO_PROXY` syntax, where
+/// each entry is one of:
+///
+///  * `*` - matches all destinations.
+///  * Domain name, for example `example.com`, matching the domain and all its
+///    subdomains. Leading `.` or `*.` is ignored.
+///  * IP address, for example `127.0.0.1` or `::1`.
+///  * CIDR range, for example `10.0.0.0/8` or `fd00::/8`.
+///
+/// Domain names and IP addresses may be followed by a port, for example
+/// `example.com:443` or `[::1]:8080`, to match only destinations with that
+/// port.
+///
+/// ```rust
+/// use socks::Bypass;
+/// use socks::ToAddr;
+///
+/// let bypass: Bypass = "localhost, .corp, 10.0.0.0/8".parse().unwrap();
+/// assert!(bypass.matches(&"git.corp:22".to_addr().unwrap()));
+/// assert!(!bypass.matches(&"example.com:80".to_addr().unwrap()));
+/// ```
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Bypass {
+    rules: Vec<Rule>,
+}
+
+/// A single bypass rule.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Rule {
+    /// Matches all destinations.
+    All,
+    /// Matches a domain and its subdomains, optionally with given port.
+    Domain(String, Option<u16>),
+    /// Matches an IP address, optionally with given port.
+    Ip(IpAddr, Option<u16>),
+    /// Matches a range of IP addresses.
+    Cidr(Cidr),
+}
+
+impl Bypass {
+    /// Creates bypass rules from `NO_PROXY` or `no_proxy` environment
+    /// variable.
+    ///
+    /// Returns an empty set of rules when neither is set. The variable is
+    /// shared with other programs which may accept a different syntax, so
+    /// unlike `from_str` entries that fail to parse are skipped.
+    pub fn from_env() -> Bypass {
+        Bypass::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Creates bypass rules from variables returned by given function.
+    #[doc(hidden)]
+    pub fn from_vars<F>(var: F) -> Bypass
+        where F: Fn(&str) -> Option<String>
+    {
+        let rules = BYPASS_VARIABLES.iter().filter_map(|name| var(name)).find(|value| !value.is_empty());
+        let rules = rules.as_ref().map_or(Vec::new(), |rules| {
+            rules.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()).filter_map(|entry| {
+                parse_rule(entry).ok()
+            }).collect()
+        });
+        Bypass { rules: rules }
+    }
+
+    /// Returns true if there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns true if the destination should bypass the proxy.
+    pub fn matches(&self, destination: &Addr) -> bool {
+        let port = destination.port();
+        let (domain, ip) = match_host(destination);
+        self.rules.iter().any(|rule| {
+            match *rule {
+                Rule::All => true,
+                Rule::Domain(ref suffix, rule_port) => {
+                    rule_port.map_or(true, |rule_port| rule_port == port) && domain.as_ref().map_or(false, |domain| {
+                        domain == suffix || (domain.ends_with(&suffix[..]) && domain[..domain.len() - suffix.len()].ends_with('.'))
+                    })
+                }
+                Rule::Ip(ref rule_ip, rule_port) => {
+                    rule_port.map_or(true, |rule_port| rule_port == port) && ip.as_ref() == Some(rule_ip)
+                }
+                Rule::Cidr(ref cidr) => ip.as_ref().map_or(false, |ip| cidr.contains(ip)),
+            }
+        })
+    }
+}
+
+impl FromStr for Bypass {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Bypass> {
+        let mut rules = Vec::new();
+        for entry in s.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            rules.push(try!(parse_rule(entry)));
+        }
+        Ok(Bypass { rules: rules })
+    }
+}
+
+/// Parses a single bypass rule.
+fn parse_rule(entry: &str) -> Result<Rule> {
+    if entry == "*" {
+        return Ok(Rule::All);
+    }
+    if entry.contains('/') {
+        return entry.parse().map(Rule::Cidr);
+    }
+    if let Ok(ip) = IpAddr::from_str(entry) {
+        return Ok(Rule::Ip(ip, None));
+    }
+    if let Ok(sa) = SocketAddr::from_str(entry) {
+        return Ok(Rule::Ip(sa.ip(), Some(sa.port())));
+    }
+    if entry.starts_with('[') && entry.ends_with(']') {
+        if let Ok(ip) = IpAddr::from_str(&entry[1..entry.len() - 1]) {
+            return Ok(Rule::Ip(ip, None));
+        }
+    }
+    let (host, port) = match entry.rfind(':') {
+        Some(i) => {
+            let port = try!(u16::from_str(&entry[i + 1..]).map_err(|_| invalid_rule(entry)));
+            (&entry[..i], Some(port))
+        }
+        None => (entry, None),
+    };
+    let host = host.trim_left_matches("*.").trim_left_matches('.').trim_right_matches('.');
+    if host.is_empty() || host.contains(|c: char| c == ':' || c == '*' || c == '[' || c == ']' || c.is_whitespace()) {
+        return Err(invalid_rule(entry));
+    }
+    Ok(Rule::Domain(host.to_lowercase(), port))
+}
+
+fn invalid_rule(entry: &str) -> Error {
+    invalid_input(format!("proxy: Invalid bypass rule {}", entry))
+}
+
+/// A range of IP addresses sharing a common prefix.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Cidr {
+    ip: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Creates a range containing a single address.
+    pub fn host(ip: IpAddr) -> Cidr {
+        let prefix = match ip {
+            IpAddr::V4(..) => 32,
+            IpAddr::V6(..) => 128,
+        };
+        Cidr { ip: ip, prefix: prefix }
+    }
+
+    /// Returns true if the range contains given address.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.ip, *ip) {
+            (IpAddr::V4(ref network), IpAddr::V4(ref ip)) => prefix_matches(&network.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(ref network), IpAddr::V6(ref ip)) => prefix_matches(&network.octets(), &ip.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let i = try!(s.find('/').ok_or_else(|| invalid_cidr(s)));
+        let ip = try!(IpAddr::from_str(&s[..i]).map_err(|_| invalid_cidr(s)));
+        let prefix = try!(u8::from_str(&s[i + 1..]).map_err(|_| invalid_cidr(s)));
+        if prefix > Cidr::host(ip).prefix {
+            return Err(invalid_cidr(s));
+        }
+        Ok(Cidr { ip: ip, prefix: prefix })
+    }
+}
+
+fn invalid_cidr(s: &str) -> Error {
+    invalid_input(format!("proxy: Invalid CIDR range {}", s))
+}
+
+/// Returns true if first `prefix` bits of both addresses are equal.
+fn prefix_matches(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let bytes = (prefix / 8) as usize;
+    let bits = prefix % 8;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    if bits == 0 {
+        return true;
+    }
+    let mask = !0u8 << (8 - bits);
+    a[bytes] & mask == b[bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use address::*;
+    use bypass::*;
+
+    fn matches(rules: &str, destination: &str) -> bool {
+        let bypass: Bypass = rules.parse().unwrap();
+        bypass.matches(&destination.to_addr().unwrap())
+    }
+
+    #[test]
+    fn match_domain_suffix() {
+        assert!(matches("example.com", "example.com:80"));
+        assert!(matches("example.com", "www.EXAMPLE.com:80"));
+        assert!(matches(".example.com", "example.com:80"));
+        assert!(matches("*.example.com", "a.b.example.com:80"));
+        assert!(!matches("example.com", "badexample.com:80"));
+        assert!(!matches("example.com", "example.org:80"));
+    }
+
+    #[test]
+    fn match_all() {
+        assert!(matches("*", "example.com:80"));
+        assert!(matches("a, *", "[::1]:80"));
+        assert!(!matches("", "example.com:80"));
+    }
+
+    #[test]
+    fn match_ip() {
+        assert!(matches("127.0.0.1", "127.0.0.1:80"));
+        assert!(!matches("127.0.0.1", "localhost:80"));
+        assert!(matches("::1", "[::1]:80"));
+        assert!(matches("[::1]", "[::1]:80"));
+        assert!(!matches("127.0.0.1", "127.0.0.2:80"));
+    }
+
+    #[test]
+    fn match_cidr() {
+        assert!(matches("10.0.0.0/8", "10.1.2.3:80"));
+        assert!(!matches("10.0.0.0/8", "11.1.2.3:80"));
+        assert!(matches("192.168.0.0/23", "192.168.1.1:80"));
+        assert!(!matches("192.168.0.0/23", "192.168.2.1:80"));
+        assert!(matches("fd00::/8", "[fd12::1]:80"));
+        assert!(!matches("fd00::/8", "10.1.2.3:80"));
+        assert!(matches("0.0.0.0/0", "1.2.3.4:80"));
+    }
+
+    #[test]
+    fn match_port() {
+        assert!(matches("example.com:443", "example.com:443"));
+        assert!(!matches("example.com:443", "example.com:80"));
+        assert!(matches("127.0.0.1:8080", "127.0.0.1:8080"));
+        assert!(!matches("127.0.0.1:8080", "127.0.0.1:80"));
+        assert!(matches("[::1]:8080", "[::1]:8080"));
+    }
+
+    #[test]
+    fn from_vars_skips_invalid() {
+        let rules = "localhost, 169.254/16, <local>, 10.0.0.0/8";
+        assert!(rules.parse::<Bypass>().is_err());
+
+        let bypass = Bypass::from_vars(|name| if name == "no_proxy" { Some(rules.to_owned()) } else { None });
+        assert!(bypass.matches(&"localhost:80".to_addr().unwrap()));
+        assert!(bypass.matches(&"10.1.2.3:80".to_addr().unwrap()));
+        assert!(!bypass.matches(&"169.254.0.1:80".to_addr().unwrap()));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!("10.0.0.0/33".parse::<Bypass>().is_err());
+        assert!("example.com:http".parse::<Bypass>().is_err());
+        assert!("a b".parse::<Bypass>().is_err());
+    }
+}
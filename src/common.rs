@@ -6,10 +6,19 @@
 
 use byteorder::BigEndian;
 use byteorder::WriteBytesExt;
+use futures::Async;
+use futures::Complete;
+use futures::Future;
+use futures::IntoFuture;
+use futures::Poll;
+use futures::oneshot;
 use std::error;
 use std::io::ErrorKind;
 use std::io;
 use std::io::Result;
+use tokio_core::io::IoFuture;
+use tokio_core::reactor::Handle;
+use tokio_core::reactor::Remote;
 
 /// Returns a new error of other kind.
 pub fn other<E>(error: E) -> io::Error
@@ -37,10 +46,67 @@ pub fn write_port(buffer: &mut Vec<u8>, port: u16) -> Result<()> {
     buffer.write_u16::<BigEndian>(port)
 }
 
+/// Runs a function on the reactor thread, resolving to the result of returned
+/// future.
+///
+/// The future on the reactor is dropped once the returned future is dropped.
+pub fn on_reactor<F, R, T>(remote: &Remote, f: F) -> IoFuture<T>
+    where F: FnOnce(&Handle) -> R + Send + 'static,
+          R: IntoFuture<Item=T, Error=io::Error>,
+          R::Future: 'static,
+          T: Send + 'static
+{
+    let (complete, result) = oneshot();
+    remote.spawn(move |handle| {
+        Cancellable {
+            future: f(handle).into_future(),
+            complete: Some(complete),
+        }
+    });
+    result.then(|result| {
+        match result {
+            Ok(result) => result,
+            Err(_) => Err(other("proxy: Reactor shut down")),
+        }
+    }).boxed()
+}
+
+/// Future completing a oneshot with the result of inner future, unless the
+/// receiving end is dropped first.
+struct Cancellable<F: Future> {
+    future: F,
+    complete: Option<Complete<Result<F::Item>>>,
+}
+
+impl<F> Future for Cancellable<F>
+    where F: Future<Error=io::Error>
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if let Ok(Async::Ready(())) = self.complete.as_mut().unwrap().poll_cancel() {
+            return Ok(Async::Ready(()));
+        }
+        let result = match self.future.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(item)) => Ok(item),
+            Err(err) => Err(err),
+        };
+        self.complete.take().unwrap().complete(result);
+        Ok(Async::Ready(()))
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use std::convert::*;
     use std::io::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::sync::MutexGuard;
+    use std::thread;
 
     /// Stream implementation used for testing purposes.
     pub struct Stream {
@@ -67,6 +133,42 @@ pub mod test {
         }
     }
 
+    /// Stream sharing its state with clones, so that it can be inspected
+    /// after being consumed.
+    #[derive(Clone)]
+    pub struct Shared(Arc<Mutex<Stream>>);
+
+    impl Shared {
+        pub fn new(bytes: &[u8]) -> Shared {
+            Shared(Arc::new(Mutex::new(Stream::new(bytes))))
+        }
+
+        /// Returns the underlying stream.
+        pub fn lock(&self) -> MutexGuard<Stream> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    /// Runs a proxy that accepts a single connection, reads messages of given
+    /// lengths and responds to each with provided reply. Returns proxy URL and
+    /// a handle resolving to all received data.
+    pub fn proxy(prefix: &str, exchanges: Vec<(usize, Vec<u8>)>) -> (String, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("{}{}", prefix, listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            for (length, reply) in exchanges {
+                let mut request = vec![0; length];
+                stream.read_exact(&mut request).unwrap();
+                stream.write_all(&reply).unwrap();
+                received.extend(request);
+            }
+            received
+        });
+        (url, handle)
+    }
+
     impl Read for Stream {
         fn read(&mut self, buff: &mut [u8]) -> Result<usize> {
             self.read_buff.read(buff)
@@ -81,5 +183,67 @@ pub mod test {
             self.write_buff.flush()
         }
     }
+
+    impl Read for Shared {
+        fn read(&mut self, buff: &mut [u8]) -> Result<usize> {
+            self.lock().read(buff)
+        }
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, buff: &[u8]) -> Result<usize> {
+            self.lock().write(buff)
+        }
+        fn flush(&mut self) -> Result<()> {
+            self.lock().flush()
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use common::*;
+    use futures::Async;
+    use futures::Future;
+    use futures::Poll;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    /// Future that never completes and records when it is dropped.
+    struct Pending(Arc<AtomicBool>);
+
+    impl Future for Pending {
+        type Item = ();
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    impl Drop for Pending {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn on_reactor_cancelled() {
+        let mut reactor = Core::new().unwrap();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let pending = Pending(dropped.clone());
+        let future = on_reactor(&reactor.remote(), move |_| pending);
+        reactor.turn(Some(Duration::from_millis(10)));
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        drop(future);
+        for _ in 0..10 {
+            reactor.turn(Some(Duration::from_millis(10)));
+        }
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2016 Tomasz Miąsko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE>
+// or the MIT license <LICENSE-MIT>, at your option. You may not use
+// this file except according to those terms.
+
+use std::error;
+use std::fmt;
+use std::io::ErrorKind;
+use std::io;
+
+/// Phase of a handshake with a proxy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Phase {
+    /// Establishing a connection with the proxy.
+    Connect,
+    /// Negotiation of protocol version and authentication method.
+    Greeting,
+    /// Method specific authentication sub-negotiation.
+    Authentication,
+    /// Request and reply exchanged after authentication.
+    Request,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let phase = match *self {
+            Phase::Connect => "connect",
+            Phase::Greeting => "greeting",
+            Phase::Authentication => "authentication",
+            Phase::Request => "request",
+        };
+        f.write_str(phase)
+    }
+}
+
+/// An error that occurred during a handshake with a proxy.
+///
+/// Errors returned from futures in this crate are `io::Error`s. When failure
+/// happens during a handshake, the `io::Error` wraps a value of this type,
+/// which can be obtained with `Error::from_io`.
+#[derive(Debug)]
+pub enum Error {
+    /// General SOCKS server failure (SOCKS5 reply 1).
+    GeneralFailure,
+    /// Connection not allowed by ruleset (SOCKS5 reply 2).
+    NotAllowed,
+    /// Network unreachable (SOCKS5 reply 3).
+    NetworkUnreachable,
+    /// Host unreachable (SOCKS5 reply 4).
+    HostUnreachable,
+    /// Connection refused (SOCKS5 reply 5).
+    ConnectionRefused,
+    /// TTL expired (SOCKS5 reply 6).
+    TtlExpired,
+    /// Command not supported (SOCKS5 reply 7).
+    CommandNotSupported,
+    /// Address type not supported (SOCKS5 reply 8).
+    AddressTypeNotSupported,
+    /// Unassigned SOCKS5 reply code.
+    UnknownReply(u8),
+    /// Request rejected or failed (SOCKS4 status 91).
+    Rejected,
+    /// Request rejected because server cannot connect to identd on the client
+    /// (SOCKS4 status 92).
+    IdentdUnreachable,
+    /// Request rejected because client and identd report different user-ids
+    /// (SOCKS4 status 93).
+    IdentdMismatch,
+    /// Unassigned SOCKS4 status code.
+    UnknownStatus(u8),
+    /// Unsuccessful HTTP status code in response to CONNECT request.
+    HttpStatus(u16),
+    /// Proxy accepts none of offered authentication methods.
+    NoAcceptableMethods,
+    /// Proxy rejected provided credentials.
+    AuthenticationFailed,
+    /// Proxy violated the protocol.
+    Protocol(Phase, String),
+    /// An I/O error.
+    Io(Phase, io::Error),
+    /// Proxy did not respond in time.
+    Timeout(Phase),
+    /// An error at given hop of a proxy chain, counting from zero.
+    Hop(usize, io::Error),
+}
+
+impl Error {
+    /// Returns the phase of handshake that failed.
+    pub fn phase(&self) -> Phase {
+        match *self {
+            Error::NoAcceptableMethods => Phase::Greeting,
+            Error::AuthenticationFailed => Phase::Authentication,
+            Error::Protocol(phase, _) => phase,
+            Error::Io(phase, _) => phase,
+            Error::Timeout(phase) => phase,
+            Error::Hop(_, ref err) => Error::from_io(err).map_or(Phase::Request, Error::phase),
+            _ => Phase::Request,
+        }
+    }
+
+    /// Returns the handshake error wrapped by an `io::Error`, if any.
+    pub fn from_io(error: &io::Error) -> Option<&Error> {
+        error.get_ref().and_then(|error| error.downcast_ref::<Error>())
+    }
+
+    /// Returns an `io::ErrorKind` most closely describing this error.
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::ConnectionRefused => ErrorKind::ConnectionRefused,
+            Error::NotAllowed => ErrorKind::PermissionDenied,
+            Error::HttpStatus(403) => ErrorKind::PermissionDenied,
+            Error::AuthenticationFailed => ErrorKind::PermissionDenied,
+            Error::NoAcceptableMethods => ErrorKind::PermissionDenied,
+            Error::TtlExpired => ErrorKind::TimedOut,
+            Error::Timeout(..) => ErrorKind::TimedOut,
+            Error::Protocol(..) => ErrorKind::InvalidData,
+            Error::Io(_, ref err) => err.kind(),
+            Error::Hop(_, ref err) => err.kind(),
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownReply(code) => write!(f, "proxy: Error {}", code),
+            Error::UnknownStatus(code) => write!(f, "proxy: Error {}", code),
+            Error::HttpStatus(code) => write!(f, "proxy: HTTP status {}", code),
+            Error::Protocol(_, ref message) => f.write_str(message),
+            Error::Io(phase, ref err) => write!(f, "proxy: I/O error during {}: {}", phase, err),
+            Error::Timeout(phase) => write!(f, "proxy: Timed out during {}", phase),
+            Error::Hop(hop, ref err) => write!(f, "{} (at hop {})", err, hop),
+            _ => f.write_str(error::Error::description(self)),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::GeneralFailure => "proxy: General SOCKS server failure",
+            Error::NotAllowed => "proxy: Connection not allowed by ruleset",
+            Error::NetworkUnreachable => "proxy: Network unreachable",
+            Error::HostUnreachable => "proxy: Host unreachable",
+            Error::ConnectionRefused => "proxy: Connection refused",
+            Error::TtlExpired => "proxy: TTL expired",
+            Error::CommandNotSupported => "proxy: Command not supported",
+            Error::AddressTypeNotSupported => "proxy: Address type not supported",
+            Error::UnknownReply(..) => "proxy: Unknown reply",
+            Error::Rejected => "proxy: Request rejected or failed",
+            Error::IdentdUnreachable => "proxy: Request rejected becasue SOCKS server cannot connect to identd on the client",
+            Error::IdentdMismatch => "proxy: Request rejected because the client program and identd report different user-ids",
+            Error::UnknownStatus(..) => "proxy: Unknown status",
+            Error::HttpStatus(..) => "proxy: Unsuccessful HTTP status",
+            Error::NoAcceptableMethods => "proxy: No acceptable authentication methods",
+            Error::AuthenticationFailed => "proxy: Authentication failure",
+            Error::Protocol(_, ref message) => message,
+            Error::Io(_, ref err) => error::Error::description(err),
+            Error::Timeout(..) => "proxy: Timed out",
+            Error::Hop(_, ref err) => error::Error::description(err),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(_, ref err) => Some(err),
+            Error::Hop(_, ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        io::Error::new(error.kind(), error)
+    }
+}
+
+/// Returns a new protocol violation error.
+pub fn protocol<M>(phase: Phase, message: M) -> io::Error
+    where M: Into<String>
+{
+    Error::Protocol(phase, message.into()).into()
+}
+
+/// Attributes an error to given handshake phase, unless it is already a
+/// handshake error or it was caused by invalid input provided by the caller.
+pub fn in_phase(phase: Phase, error: io::Error) -> io::Error {
+    if Error::from_io(&error).is_some() || error.kind() == ErrorKind::InvalidInput {
+        error
+    } else {
+        Error::Io(phase, error).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::*;
+    use std::io::ErrorKind;
+    use std::io;
+
+    #[test]
+    fn into_io_error() {
+        let error: io::Error = Error::ConnectionRefused.into();
+        assert_eq!(ErrorKind::ConnectionRefused, error.kind());
+        assert_eq!("proxy: Connection refused", format!("{}", error));
+        match Error::from_io(&error) {
+            Some(&Error::ConnectionRefused) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn phase_of_io_error() {
+        let error = io::Error::new(ErrorKind::UnexpectedEof, "eof");
+        let error = in_phase(Phase::Greeting, error);
+        let error = in_phase(Phase::Request, error);
+        assert_eq!(ErrorKind::UnexpectedEof, error.kind());
+        assert_eq!(Phase::Greeting, Error::from_io(&error).unwrap().phase());
+    }
+
+    #[test]
+    fn hop_error() {
+        let error: io::Error = Error::Hop(1, Error::AuthenticationFailed.into()).into();
+        assert_eq!(ErrorKind::PermissionDenied, error.kind());
+        assert_eq!(Phase::Authentication, Error::from_io(&error).unwrap().phase());
+        assert_eq!("proxy: Authentication failure (at hop 1)", format!("{}", error));
+    }
+
+    #[test]
+    fn kind_of_not_allowed() {
+        assert_eq!(ErrorKind::PermissionDenied, Error::NotAllowed.kind());
+        assert_eq!(ErrorKind::PermissionDenied, Error::AuthenticationFailed.kind());
+        assert_eq!(Phase::Authentication, Error::AuthenticationFailed.phase());
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2016 Tomasz Miąsko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE>
+// or the MIT license <LICENSE-MIT>, at your option. You may not use
+// this file except according to those terms.
+
+use common::*;
+use futures::Async;
+use futures::Future;
+use futures::Poll;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::vec;
+use tokio_core::net::TcpStream;
+use tokio_core::net::TcpStreamNew;
+use tokio_core::reactor::Handle;
+use tokio_core::reactor::Timeout;
+
+/// Configuration of connection racing to a proxy host with multiple
+/// addresses, as described in RFC 8305 (Happy Eyeballs).
+///
+/// Addresses are ordered so that families alternate, starting with the
+/// preferred one. Connection attempts are started one after another,
+/// separated by a delay or by failure of previous attempts, and the first
+/// established connection is used.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct HappyEyeballs {
+    /// Delay between starting consecutive connection attempts.
+    pub delay: Duration,
+    /// Whether IPv6 addresses are attempted first.
+    pub prefer_ipv6: bool,
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> HappyEyeballs {
+        HappyEyeballs {
+            delay: Duration::from_millis(250),
+            prefer_ipv6: true,
+        }
+    }
+}
+
+/// Races connections to given addresses.
+pub fn connect(addresses: Vec<SocketAddr>, config: &HappyEyeballs, handle: &Handle) -> Race {
+    Race {
+        addresses: interleave(addresses, config.prefer_ipv6).into_iter(),
+        attempts: Vec::new(),
+        delay: config.delay,
+        timer: None,
+        handle: handle.clone(),
+        error: None,
+    }
+}
+
+/// Orders addresses so that families alternate, starting with the preferred
+/// one.
+fn interleave(addresses: Vec<SocketAddr>, prefer_ipv6: bool) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<_>, Vec<_>) = addresses.into_iter().partition(|address| {
+        match *address {
+            SocketAddr::V4(..) => !prefer_ipv6,
+            SocketAddr::V6(..) => prefer_ipv6,
+        }
+    });
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut result = Vec::new();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return result,
+            (first, second) => {
+                result.extend(first);
+                result.extend(second);
+            }
+        }
+    }
+}
+
+/// Future resolving to the first established connection.
+pub struct Race {
+    addresses: vec::IntoIter<SocketAddr>,
+    attempts: Vec<TcpStreamNew>,
+    delay: Duration,
+    timer: Option<Timeout>,
+    handle: Handle,
+    error: Option<io::Error>,
+}
+
+impl Future for Race {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, io::Error> {
+        loop {
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].poll() {
+                    Ok(Async::Ready(stream)) => return Ok(Async::Ready(stream)),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(err) => {
+                        self.attempts.remove(i);
+                        self.error = Some(err);
+                    }
+                }
+            }
+            // Next attempt starts after a delay, or right away when all
+            // previous attempts failed.
+            let start = self.attempts.is_empty() || match self.timer {
+                Some(ref mut timer) => try!(timer.poll()).is_ready(),
+                None => true,
+            };
+            if !start {
+                return Ok(Async::NotReady);
+            }
+            match self.addresses.next() {
+                Some(address) => {
+                    self.attempts.push(TcpStream::connect(&address, &self.handle));
+                    self.timer = Some(try!(Timeout::new(self.delay, &self.handle)));
+                }
+                None if self.attempts.is_empty() => {
+                    return Err(self.error.take().unwrap_or_else(|| {
+                        other("proxy: No addresses to connect to")
+                    }));
+                }
+                None => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyeballs::*;
+    use std::net::SocketAddr;
+    use std::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn interleave_families() {
+        let addresses = vec![addr("1.1.1.1:80"), addr("2.2.2.2:80"), addr("[::1]:80"), addr("[::2]:80"), addr("3.3.3.3:80")];
+        assert_eq!(vec![addr("[::1]:80"), addr("1.1.1.1:80"), addr("[::2]:80"), addr("2.2.2.2:80"), addr("3.3.3.3:80")],
+                   interleave(addresses.clone(), true));
+        assert_eq!(vec![addr("1.1.1.1:80"), addr("[::1]:80"), addr("2.2.2.2:80"), addr("[::2]:80"), addr("3.3.3.3:80")],
+                   interleave(addresses, false));
+    }
+
+    #[test]
+    fn connect_after_failure() {
+        // Bind and drop a listener to obtain an address refusing connections.
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut reactor = Core::new().unwrap();
+        let config = HappyEyeballs::default();
+        let race = connect(vec![refused, address], &config, &reactor.handle());
+        let stream = reactor.run(race).unwrap();
+        assert_eq!(address, stream.peer_addr().unwrap());
+    }
+
+    #[test]
+    fn connect_no_addresses() {
+        let mut reactor = Core::new().unwrap();
+        let race = connect(Vec::new(), &HappyEyeballs::default(), &reactor.handle());
+        assert!(reactor.run(race).is_err());
+    }
+}
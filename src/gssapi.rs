@@ -0,0 +1,552 @@
+// Copyright 2016 Tomasz Miąsko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE>
+// or the MIT license <LICENSE-MIT>, at your option. You may not use
+// this file except according to those terms.
+
+//! Implementation of GSSAPI authentication method for SOCKS5 (RFC 1961).
+//!
+//! GSSAPI itself is not implemented by this crate. Instead security context
+//! is provided by a caller through `SecurityContext` trait.
+
+use address::Addr;
+use address::ToAddr;
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+use byteorder::WriteBytesExt;
+use common::*;
+use error::Error;
+use error::Phase;
+use error::protocol;
+use futures::Async;
+use futures::Future;
+use futures::done;
+use futures::failed;
+use futures::finished;
+use self::consts::*;
+use std::cmp;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::io::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use stream::ProxiedStream;
+use tokio_core::io::Io;
+use tokio_core::io::IoFuture;
+use tokio_core::io::read_exact;
+use tokio_core::io::write_all;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use v5::Authenticator;
+use v5;
+
+/// A GSSAPI security context initiated by the client.
+///
+/// Corresponds to a subset of GSSAPI functionality that is necessary to
+/// establish a context with the proxy and protect exchanged messages.
+pub trait SecurityContext: Send + 'static {
+    /// Processes a token received from the proxy and returns a token to be
+    /// sent to the proxy, if any. The first call is made without a token.
+    ///
+    /// Equivalent of `gss_init_sec_context`.
+    fn step(&mut self, token: Option<&[u8]>) -> Result<Option<Vec<u8>>>;
+
+    /// Returns true when context has been established.
+    fn is_established(&self) -> bool;
+
+    /// Protects a message, with confidentiality if requested.
+    ///
+    /// Equivalent of `gss_wrap`.
+    fn wrap(&mut self, message: &[u8], confidential: bool) -> Result<Vec<u8>>;
+
+    /// Verifies a protected message and returns its content.
+    ///
+    /// Equivalent of `gss_unwrap`.
+    fn unwrap(&mut self, token: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Level of protection applied to messages exchanged after authentication.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protection {
+    /// Per-message integrity.
+    Integrity,
+    /// Per-message integrity and confidentiality.
+    Confidentiality,
+    /// Selective per-message integrity or confidentiality based on proxy
+    /// configuration. Messages sent by the client are always confidential.
+    Selective,
+}
+
+impl Protection {
+    fn to_u8(self) -> u8 {
+        match self {
+            Protection::Integrity => 1,
+            Protection::Confidentiality => 2,
+            Protection::Selective => 3,
+        }
+    }
+
+    fn from_u8(level: u8) -> Option<Protection> {
+        match level {
+            1 => Some(Protection::Integrity),
+            2 => Some(Protection::Confidentiality),
+            3 => Some(Protection::Selective),
+            _ => None,
+        }
+    }
+}
+
+/// GSSAPI authentication method.
+///
+/// Establishes a security context with the proxy and negotiates a protection
+/// level. Once negotiation completes, all further communication with the
+/// proxy must be encapsulated using `wrap`.
+pub struct Gssapi<C> {
+    context: Arc<Mutex<C>>,
+    requested: Protection,
+    negotiated: Arc<Mutex<Option<Protection>>>,
+}
+
+impl<C: SecurityContext> Gssapi<C> {
+    /// Creates a new GSSAPI authentication method using given security context
+    /// and requesting given protection level.
+    pub fn new(context: C, protection: Protection) -> Gssapi<C> {
+        Gssapi {
+            context: Arc::new(Mutex::new(context)),
+            requested: protection,
+            negotiated: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Returns protection level selected by the proxy, once negotiated.
+    pub fn negotiated(&self) -> Option<Protection> {
+        *self.negotiated.lock().unwrap()
+    }
+
+    /// Wraps a stream in per-message protection negotiated with the proxy.
+    pub fn wrap<S>(&self, stream: S) -> Result<GssapiStream<S, C>> {
+        match self.negotiated() {
+            Some(protection) => Ok(GssapiStream::new(stream, self.context.clone(), protection)),
+            None => Err(other("proxy: GSSAPI protection level has not been negotiated")),
+        }
+    }
+}
+
+impl<C> Clone for Gssapi<C> {
+    fn clone(&self) -> Gssapi<C> {
+        Gssapi {
+            context: self.context.clone(),
+            requested: self.requested,
+            negotiated: self.negotiated.clone(),
+        }
+    }
+}
+
+impl<C: SecurityContext> Authenticator for Gssapi<C> {
+    fn methods(&self) -> Vec<u8> {
+        vec![AUTH_GSSAPI]
+    }
+
+    fn authenticate<S>(&self, _: u8, stream: S) -> IoFuture<S>
+        where S: Read + Write + Send + 'static
+    {
+        let context = self.context.clone();
+        let requested = self.requested;
+        let negotiated = self.negotiated.clone();
+        establish(stream, self.context.clone(), None).and_then(move |stream| {
+            negotiate_protection(stream, context, requested)
+        }).map(move |(stream, protection)| {
+            *negotiated.lock().unwrap() = Some(protection);
+            stream
+        }).boxed()
+    }
+}
+
+/// Crates a new connection through a SOCKS5 proxy using GSSAPI
+/// authentication.
+pub fn connect<D, C>(proxy: &SocketAddr, destination: D, context: C, protection: Protection, handle: &Handle) -> IoFuture<ProxiedStream<GssapiStream<TcpStream, C>>>
+    where D: ToAddr, C: SecurityContext
+{
+    let connection = TcpStream::connect(&proxy, handle);
+    let proxy = *proxy;
+    Box::new(done(destination.to_addr()).and_then(|address| {
+        connection.and_then(|stream| {
+            connect_stream(stream, address, context, protection)
+        })
+    }).and_then(move |stream| {
+        proxy.to_addr().map(|proxy| stream.with_proxy(proxy))
+    }))
+}
+
+/// Crates a new connection through a SOCKS5 proxy using GSSAPI
+/// authentication over an existing stream.
+#[doc(hidden)]
+pub fn connect_stream<S, C>(stream: S, destination: Addr, context: C, protection: Protection) -> IoFuture<ProxiedStream<GssapiStream<S, C>>>
+    where S: Read + Write + Send + 'static, C: SecurityContext
+{
+    let auth = Gssapi::new(context, protection);
+    v5::negotiate(stream, auth.clone()).and_then(move |(stream, method)| {
+        done(auth.wrap(stream)).map(move |stream| (stream, method))
+    }).and_then(move |(stream, method)| {
+        v5::connect_negotiated(stream, destination, method)
+    }).boxed()
+}
+
+/// Exchanges tokens with the proxy until security context is established.
+fn establish<S, C>(stream: S, context: Arc<Mutex<C>>, token: Option<Vec<u8>>) -> IoFuture<S>
+    where S: Read + Write + Send + 'static, C: SecurityContext
+{
+    let (output, established) = {
+        let mut context = context.lock().unwrap();
+        match context.step(token.as_ref().map(|token| &token[..])) {
+            Ok(output) => (output, context.is_established()),
+            Err(err) => return failed(err).boxed(),
+        }
+    };
+    match output {
+        Some(output) => {
+            write_message(stream, MTYP_AUTH, &output).and_then(move |stream| {
+                if established {
+                    return finished(stream).boxed();
+                }
+                read_message(stream, MTYP_AUTH).and_then(move |(stream, token)| {
+                    establish(stream, context, Some(token))
+                }).boxed()
+            }).boxed()
+        }
+        None if established => finished(stream).boxed(),
+        None => failed(other("proxy: GSSAPI context produced no token")).boxed(),
+    }
+}
+
+/// Negotiates per-message protection level with the proxy.
+fn negotiate_protection<S, C>(stream: S, context: Arc<Mutex<C>>, requested: Protection) -> IoFuture<(S, Protection)>
+    where S: Read + Write + Send + 'static, C: SecurityContext
+{
+    let token = context.lock().unwrap().wrap(&[requested.to_u8()], false);
+    done(token).and_then(|token| {
+        write_message(stream, MTYP_PROTECTION, &token)
+    }).and_then(|stream| {
+        read_message(stream, MTYP_PROTECTION)
+    }).and_then(move |(stream, token)| {
+        let level = try!(context.lock().unwrap().unwrap(&token));
+        if level.len() != 1 {
+            return Err(protocol(Phase::Authentication, "proxy: Invalid GSSAPI protection level message"));
+        }
+        match Protection::from_u8(level[0]) {
+            Some(protection) => Ok((stream, protection)),
+            None => Err(protocol(Phase::Authentication, format!("proxy: Invalid GSSAPI protection level {}", level[0]))),
+        }
+    }).boxed()
+}
+
+/// Encodes a GSSAPI message with given type and token.
+fn encode_message(mtyp: u8, token: &[u8]) -> Result<Vec<u8>> {
+    if token.len() > u16::max_value() as usize {
+        return Err(invalid_input("proxy: GSSAPI token length exceeds 65535 bytes"));
+    }
+    let mut buffer = vec![GSSAPI_VERSION, mtyp];
+    try!(buffer.write_u16::<BigEndian>(token.len() as u16));
+    try!(buffer.write(token));
+    Ok(buffer)
+}
+
+fn write_message<S>(stream: S, mtyp: u8, token: &[u8]) -> IoFuture<S>
+    where S: Write + Send + 'static
+{
+    done(encode_message(mtyp, token)).and_then(|buffer| {
+        write_all(stream, buffer)
+    }).map(|(stream, _)| {
+        stream
+    }).boxed()
+}
+
+fn read_message<S>(stream: S, mtyp: u8) -> IoFuture<(S, Vec<u8>)>
+    where S: Read + Send + 'static
+{
+    read_exact(stream, vec![0; 2]).and_then(move |(stream, buffer)| {
+        if buffer[0] != GSSAPI_VERSION {
+            return Err(protocol(Phase::Authentication, "proxy: Invalid GSSAPI version in response"))
+        }
+        if buffer[1] == MTYP_ABORT {
+            return Err(Error::AuthenticationFailed.into())
+        }
+        if buffer[1] != mtyp {
+            return Err(protocol(Phase::Authentication, "proxy: Unexpected GSSAPI message type in response"))
+        }
+        Ok((stream, buffer))
+    }).and_then(|(stream, buffer)| {
+        read_exact(stream, buffer)
+    }).and_then(|(stream, mut buffer)| {
+        let length = BigEndian::read_u16(&buffer) as usize;
+        buffer.resize(length, 0);
+        read_exact(stream, buffer)
+    }).boxed()
+}
+
+/// A stream encapsulating data in GSSAPI protected messages.
+///
+/// Each write is sent as a separate message and may be buffered internally,
+/// so `flush` should be used to ensure that all data have been written to the
+/// underlying stream.
+pub struct GssapiStream<S, C> {
+    stream: S,
+    context: Arc<Mutex<C>>,
+    confidential: bool,
+    /// Received data that do not form a complete message yet.
+    read_buff: Vec<u8>,
+    /// Unwrapped data not returned to the reader yet.
+    plain_buff: Vec<u8>,
+    plain_pos: usize,
+    /// Encoded messages not written to the underlying stream yet.
+    write_buff: Vec<u8>,
+}
+
+impl<S, C: SecurityContext> GssapiStream<S, C> {
+    fn new(stream: S, context: Arc<Mutex<C>>, protection: Protection) -> GssapiStream<S, C> {
+        GssapiStream {
+            stream: stream,
+            context: context,
+            confidential: protection != Protection::Integrity,
+            read_buff: Vec::new(),
+            plain_buff: Vec::new(),
+            plain_pos: 0,
+            write_buff: Vec::new(),
+        }
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S { &self.stream }
+
+    /// Returns a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut S { &mut self.stream }
+
+    /// Removes a complete message from the read buffer and returns its
+    /// unwrapped content.
+    fn decode(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.read_buff.len() < 2 {
+            return Ok(None);
+        }
+        if self.read_buff[0] != GSSAPI_VERSION {
+            return Err(invalid_data("proxy: Invalid GSSAPI version in message"));
+        }
+        if self.read_buff[1] == MTYP_ABORT {
+            return Err(other("proxy: GSSAPI context aborted"));
+        }
+        if self.read_buff[1] != MTYP_ENCAPSULATION {
+            return Err(invalid_data("proxy: Unexpected GSSAPI message type"));
+        }
+        if self.read_buff.len() < 4 {
+            return Ok(None);
+        }
+        let length = BigEndian::read_u16(&self.read_buff[2..4]) as usize + 4;
+        if self.read_buff.len() < length {
+            return Ok(None);
+        }
+        let plain = try!(self.context.lock().unwrap().unwrap(&self.read_buff[4..length]));
+        self.read_buff.drain(..length);
+        Ok(Some(plain))
+    }
+
+    /// Writes pending messages to the underlying stream.
+    fn write_pending(&mut self) -> Result<()>
+        where S: Write
+    {
+        while !self.write_buff.is_empty() {
+            let n = try!(self.stream.write(&self.write_buff));
+            if n == 0 {
+                return Err(io::Error::new(ErrorKind::WriteZero, "failed to write GSSAPI message"));
+            }
+            self.write_buff.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read, C: SecurityContext> Read for GssapiStream<S, C> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.plain_pos < self.plain_buff.len() {
+                let n = cmp::min(buf.len(), self.plain_buff.len() - self.plain_pos);
+                buf[..n].copy_from_slice(&self.plain_buff[self.plain_pos..self.plain_pos + n]);
+                self.plain_pos += n;
+                return Ok(n);
+            }
+            if let Some(plain) = try!(self.decode()) {
+                self.plain_buff = plain;
+                self.plain_pos = 0;
+                continue;
+            }
+            let mut chunk = [0; 4096];
+            let n = try!(self.stream.read(&mut chunk));
+            if n == 0 {
+                if self.read_buff.is_empty() {
+                    return Ok(0);
+                }
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated GSSAPI message"));
+            }
+            self.read_buff.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: Write, C: SecurityContext> Write for GssapiStream<S, C> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        try!(self.write_pending());
+        let n = cmp::min(buf.len(), MAX_MESSAGE);
+        let token = try!(self.context.lock().unwrap().wrap(&buf[..n], self.confidential));
+        self.write_buff = try!(encode_message(MTYP_ENCAPSULATION, &token));
+        match self.write_pending() {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+            Ok(()) => {}
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        try!(self.write_pending());
+        self.stream.flush()
+    }
+}
+
+impl<S: Io, C: SecurityContext> Io for GssapiStream<S, C> {
+    fn poll_read(&mut self) -> Async<()> {
+        if self.plain_pos < self.plain_buff.len() || !self.read_buff.is_empty() {
+            Async::Ready(())
+        } else {
+            self.stream.poll_read()
+        }
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.stream.poll_write()
+    }
+}
+
+/// Constants used in GSSAPI authentication method.
+mod consts {
+    pub const AUTH_GSSAPI: u8 = 1;
+    pub const GSSAPI_VERSION: u8 = 1;
+    pub const MTYP_AUTH: u8 = 1;
+    pub const MTYP_PROTECTION: u8 = 2;
+    pub const MTYP_ENCAPSULATION: u8 = 3;
+    pub const MTYP_ABORT: u8 = 0xff;
+    /// Maximum size of data sent in a single message, leaving space for
+    /// overhead introduced by wrapping.
+    pub const MAX_MESSAGE: usize = 32768;
+}
+
+#[cfg(test)]
+mod tests {
+    use address::*;
+    use common::test::*;
+    use gssapi::*;
+    use gssapi::consts::*;
+    use std::io::Read;
+    use std::io::Result;
+    use std::io::Write;
+    use tokio_core::reactor::Core;
+
+    const VERSION: u8 = 5;
+    const CMD_CONNECT: u8 = 1;
+    const ATYP_IPV4: u8 = 1;
+
+    /// Security context completing after a single round trip, which marks
+    /// wrapped messages by prepending a byte to them.
+    struct Mock {
+        established: bool,
+    }
+
+    impl SecurityContext for Mock {
+        fn step(&mut self, token: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+            match token {
+                None => Ok(Some(b"hello".to_vec())),
+                Some(token) => {
+                    assert_eq!(b"world", token);
+                    self.established = true;
+                    Ok(None)
+                }
+            }
+        }
+
+        fn is_established(&self) -> bool {
+            self.established
+        }
+
+        fn wrap(&mut self, message: &[u8], confidential: bool) -> Result<Vec<u8>> {
+            let mut token = vec![if confidential { b'c' } else { b'i' }];
+            token.extend_from_slice(message);
+            Ok(token)
+        }
+
+        fn unwrap(&mut self, token: &[u8]) -> Result<Vec<u8>> {
+            Ok(token[1..].to_vec())
+        }
+    }
+
+    #[test]
+    fn connect_confidential() {
+        let stream = Stream::new(&[
+            VERSION, AUTH_GSSAPI,
+            GSSAPI_VERSION, MTYP_AUTH, 0, 5, b'w', b'o', b'r', b'l', b'd',
+            GSSAPI_VERSION, MTYP_PROTECTION, 0, 2, b'i', 2,
+            GSSAPI_VERSION, MTYP_ENCAPSULATION, 0, 11, b'c',
+            VERSION, 0, 0, ATYP_IPV4, 9, 9, 9, 9, 0, 80,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "1.2.3.4:80".to_addr().unwrap();
+        let context = Mock { established: false };
+        let stream = reactor.run(connect_stream(stream, address, context, Protection::Confidentiality)).unwrap();
+
+        assert_eq!("9.9.9.9:80".to_addr().unwrap(), *stream.bound_addr());
+        assert_eq!(Some(AUTH_GSSAPI), stream.auth_method());
+        assert!(stream.get_ref().get_ref().read_all());
+        assert_eq!([VERSION, 1, AUTH_GSSAPI,
+                    GSSAPI_VERSION, MTYP_AUTH, 0, 5, b'h', b'e', b'l', b'l', b'o',
+                    GSSAPI_VERSION, MTYP_PROTECTION, 0, 2, b'i', 2,
+                    GSSAPI_VERSION, MTYP_ENCAPSULATION, 0, 11, b'c',
+                    VERSION, CMD_CONNECT, 0, ATYP_IPV4, 1, 2, 3, 4, 0, 80],
+                   stream.get_ref().get_ref().write_buffer());
+    }
+
+    #[test]
+    fn connect_aborted() {
+        let stream = Stream::new(&[
+            VERSION, AUTH_GSSAPI,
+            GSSAPI_VERSION, MTYP_ABORT,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "1.2.3.4:80".to_addr().unwrap();
+        let context = Mock { established: false };
+        let error = reactor.run(connect_stream(stream, address, context, Protection::Integrity)).err().unwrap();
+
+        assert_eq!("proxy: Authentication failure", format!("{}", error));
+    }
+
+    #[test]
+    fn stream_encapsulation() {
+        let auth = Gssapi::new(Mock { established: true }, Protection::Integrity);
+        *auth.negotiated.lock().unwrap() = Some(Protection::Integrity);
+        let mut stream = auth.wrap(Stream::new(&[
+            GSSAPI_VERSION, MTYP_ENCAPSULATION, 0, 3, b'i', b'o', b'k',
+        ])).unwrap();
+
+        stream.write_all(b"ping").unwrap();
+        stream.flush().unwrap();
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).unwrap();
+
+        assert_eq!(b"ok", &buffer[..]);
+        assert_eq!([GSSAPI_VERSION, MTYP_ENCAPSULATION, 0, 5, b'i', b'p', b'i', b'n', b'g'],
+                   stream.get_ref().write_buffer());
+    }
+}
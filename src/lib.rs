@@ -36,31 +36,57 @@
 
 extern crate byteorder;
 extern crate futures;
+#[cfg(feature = "tls")]
+extern crate native_tls;
 extern crate tokio_core;
 extern crate tokio_dns;
+#[cfg(feature = "tls")]
+extern crate tokio_tls;
+#[cfg(unix)]
+extern crate tokio_uds;
 extern crate url;
 
 mod address;
+mod bypass;
+mod client;
 mod common;
+mod error;
+mod eyeballs;
+mod proxy_url;
+mod router;
+mod stream;
+mod transport;
 
+pub mod gssapi;
+pub mod http;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod v4;
 pub mod v5;
 
+pub use address::Addr;
+pub use address::Dns;
+pub use address::DomainAddr;
 pub use address::ToAddr;
+pub use bypass::Bypass;
+pub use client::Client;
+pub use client::ProxyConfig;
+pub use client::Timeouts;
+pub use error::Error;
+pub use error::Phase;
+pub use eyeballs::HappyEyeballs;
+pub use proxy_url::ProxyUrl;
+pub use router::Route;
+pub use router::Router;
+pub use router::RoutingTable;
+pub use stream::ProxiedStream;
+pub use transport::Transport;
 
-use address::Addr;
-use address::DomainAddr;
-use common::*;
 use futures::Future;
-use futures::done;
-use std::net::SocketAddrV4;
-use std::net::SocketAddrV6;
+use futures::failed;
+use std::env;
 use tokio_core::io::IoFuture;
-use tokio_core::net::TcpStream;
 use tokio_core::reactor::Remote;
-use tokio_dns::tcp_connect;
-use url::Host;
-use url::Url;
 
 /// Creates a new connection using provided proxy URL.
 ///
@@ -68,55 +94,448 @@ use url::Url;
 ///
 /// `protocol://[username:password@]host:port`
 /// 
-/// Where protocol is one of `socks4`, `socks4a` or `socks5`. Note that only
-/// version 5 of SOCKS protocol supports username-password authentication.
+/// Where protocol is one of `socks4`, `socks4a`, `socks5`, `socks5h` or
+/// `http`. With `socks4` and `socks5` destination domain names are resolved
+/// locally, while with `socks4a`, `socks5h` and `http` they are resolved by
+/// the proxy. Note that only version 5 of SOCKS protocol supports
+/// username-password authentication. When credentials are provided, proxy may
+/// also choose not to authenticate. In version 4 the username is sent as a
+/// user ID, while password is ignored. HTTP proxies are asked to open a tunnel
+/// with CONNECT method, using Basic authentication if credentials are given.
+/// Credentials are percent-decoded into raw bytes, so reserved characters such
+/// as `@`, `:` and `%` must be percent-encoded.
 ///
-pub fn connect<D>(proxy_url: &str, destination: D, remote: Remote) -> IoFuture<TcpStream>
+/// With `tls` feature enabled, protocol may be followed by `+tls` suffix, for
+/// example `socks5+tls`, to communicate with the proxy over TLS, while `https`
+/// is HTTP proxy over TLS. TLS is configured with following query parameters:
+///
+///  * `ca` - path to an additional trusted root certificate in DER format,
+///    may be repeated to trust multiple certificates.
+///  * `cert` - path to a client identity in PKCS #12 format.
+///  * `cert_password` - password of the client identity.
+///  * `sni` - server name to use instead of proxy host.
+///
+/// On Unix platforms, protocol may be followed by `+unix` suffix to connect to
+/// a proxy listening on Unix domain socket, with path given in place of host
+/// and port, for example `socks5+unix:///run/tor/socks.sock`.
+///
+/// A comma-separated list of proxy URLs establishes a proxy chain, as
+/// described in `connect_chain`. Only commas followed by another URL separate
+/// the list, but to avoid ambiguity commas in credentials should be
+/// percent-encoded as `%2C`.
+///
+/// Passwords never appear in error messages. URLs may be validated in advance
+/// by parsing them into `ProxyUrl`.
+///
+pub fn connect<D>(proxy_url: &str, destination: D, remote: Remote) -> IoFuture<ProxiedStream<Transport>>
     where D: ToAddr 
 {
-    done((|| {
-        let url = match Url::parse(proxy_url) {
-            Ok(url) => url,
-            Err(err) => return Err(invalid_input(format!("proxy: {}: {}", err, proxy_url))),
-        };
-        let version = match url.scheme() {
-            "socks4"  => Version::V4,
-            "socks4a" => Version::V4,
-            "socks5"  => Version::V5,
-            _ => return Err(invalid_input(format!("proxy: Unsupported scheme {}", url.scheme()))),
-        };
-        let host = match url.host() {
-            Some(host) => host,
-            None => return Err(invalid_input(format!("proxy: Missing host {}", proxy_url))),
-        };
-        let port = match url.port_or_known_default() {
-            Some(port) => port,
-            None => return Err(invalid_input(format!("proxy: Missing port {}", proxy_url))),
-        };
-        let address = match host {
-            Host::Domain(domain) => Addr::Domain(DomainAddr::new(domain, port)),
-            Host::Ipv4(ip)  => Addr::V4(SocketAddrV4::new(ip, port)),
-            Host::Ipv6(ip)  => Addr::V6(SocketAddrV6::new(ip, port, 0, 0)),
-        };
-        let username = url.username();
-        let password = url.password().unwrap_or("");
-        let auth = if !username.is_empty() || !password.is_empty() {
-            v5::Auth::UserPass(username.to_owned(), password.to_owned())
-        } else {
-            v5::Auth::None
-        };
-        let destination = try!(destination.to_addr());
-        Ok((version, address, destination, auth))
-    })()).and_then(move |(version, address, destination, auth)| {
-        tcp_connect(&address, remote).and_then(move |stream| {
-            match version {
-                Version::V4 => v4::connect_stream(stream, destination),
-                Version::V5 => v5::connect_stream(stream, destination, auth),
-            }
-        })
-    }).boxed()
+    connect_timeout(proxy_url, destination, &Timeouts::default(), remote)
+}
+
+/// Creates a new connection using provided proxy URL, failing when proxy does
+/// not respond within given timeouts.
+///
+/// Proxy URL has the same format as in `connect`. Expired timeouts result in
+/// `Error::Timeout` of `ErrorKind::TimedOut` kind, which identifies the phase
+/// that stalled.
+pub fn connect_timeout<D>(proxy_url: &str, destination: D, timeouts: &Timeouts, remote: Remote) -> IoFuture<ProxiedStream<Transport>>
+    where D: ToAddr
+{
+    connect_with(proxy_url, destination, timeouts, &HappyEyeballs::default(), remote)
+}
+
+/// Creates a new connection using provided proxy URL, timeouts and
+/// configuration of connection racing.
+///
+/// Proxy URL has the same format as in `connect`. When the proxy host
+/// resolves to multiple addresses, connections to them are raced as described
+/// in `HappyEyeballs`.
+pub fn connect_with<D>(proxy_url: &str, destination: D, timeouts: &Timeouts, happy_eyeballs: &HappyEyeballs, remote: Remote) -> IoFuture<ProxiedStream<Transport>>
+    where D: ToAddr
+{
+    match ProxyConfig::from_url(proxy_url) {
+        Ok(config) => {
+            let config = config.timeouts(*timeouts).happy_eyeballs(*happy_eyeballs);
+            Client::new(config, remote).connect(destination)
+        }
+        Err(err) => failed(err).boxed(),
+    }
 }
 
-/// Version of SOCKS protocol.
-enum Version { V4, V5 }
+/// Creates a new connection through a chain of proxies.
+///
+/// Connects to the first proxy, then through each proxy connects to the next
+/// one, and finally the last proxy connects to the destination. Each proxy
+/// resolves the address of the next hop according to its own DNS policy.
+/// Returned stream describes the connection made by the last proxy, while its
+/// proxy address is the address of the first one.
+///
+/// When a chain consists of more than one proxy, errors are wrapped in
+/// `Error::Hop` identifying the proxy that failed.
+pub fn connect_chain<D>(proxy_urls: &[&str], destination: D, remote: Remote) -> IoFuture<ProxiedStream<Transport>>
+    where D: ToAddr
+{
+    match ProxyConfig::from_urls(proxy_urls) {
+        Ok(config) => Client::new(config, remote).connect(destination),
+        Err(err) => failed(err).boxed(),
+    }
+}
+
+/// Creates a new connection using proxy configured in environment variables,
+/// or a direct connection when there is none.
+///
+/// Proxy URL is taken from `ALL_PROXY`, `all_proxy` or `SOCKS_PROXY`, as
+/// described in `ProxyConfig::from_env`. Destinations matching rules from
+/// `NO_PROXY` or `no_proxy`, as described in `Bypass`, are connected to
+/// directly.
+pub fn connect_auto<D>(destination: D, remote: Remote) -> IoFuture<Transport>
+    where D: ToAddr
+{
+    connect_vars(destination, |name| env::var(name).ok(), remote)
+}
+
+/// Creates a new connection using proxy configured in variables returned by
+/// given function, or a direct connection when there is none.
+fn connect_vars<D, F>(destination: D, var: F, remote: Remote) -> IoFuture<Transport>
+    where D: ToAddr, F: Fn(&str) -> Option<String>
+{
+    let config = match ProxyConfig::from_vars(&var) {
+        Ok(config) => config,
+        Err(err) => return failed(err).boxed(),
+    };
+    let bypass = Bypass::from_vars(&var);
+    let destination = match destination.to_addr() {
+        Ok(destination) => destination,
+        Err(err) => return failed(err).boxed(),
+    };
+    match config {
+        Some(config) if !bypass.matches(&destination) => {
+            Client::new(config, remote).connect(destination).map(ProxiedStream::into_inner).boxed()
+        }
+        _ => client::connect_direct(destination, HappyEyeballs::default(), remote),
+    }
+}
+
+/// Protocol used to communicate with a proxy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Version {
+    /// SOCKS version 4, including 4a extension
+    V4,
+    /// SOCKS version 5
+    V5,
+    /// HTTP CONNECT method
+    Http,
+}
+
+#[cfg(test)]
+mod tests {
+    use Error;
+    use Phase;
+    use Timeouts;
+    use common::test::*;
+    use connect;
+    use connect_timeout;
+    use connect_vars;
+    use std::io::ErrorKind;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    fn run(url: &str, destination: &str) {
+        let mut reactor = Core::new().unwrap();
+        let remote = reactor.remote();
+        reactor.run(connect(url, destination, remote)).unwrap();
+    }
+
+    const SOCKS4_REPLY: [u8; 8] = [0, 90, 0, 0, 0, 0, 0, 0];
+    const SOCKS5_REPLY: [u8; 10] = [5, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn connect_vars_direct() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut reactor = Core::new().unwrap();
+        let remote = reactor.remote();
+        reactor.run(connect_vars(address, |_| None, remote)).unwrap();
+
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(address.ip(), peer.ip());
+    }
 
+    #[test]
+    fn connect_vars_bypass() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut reactor = Core::new().unwrap();
+        let remote = reactor.remote();
+        let var = |name: &str| match name {
+            // Nothing listens on the proxy port, so only a direct connection succeeds.
+            "ALL_PROXY" => Some("socks5://127.0.0.1:1".to_owned()),
+            "NO_PROXY" => Some("127.0.0.1".to_owned()),
+            _ => None,
+        };
+        reactor.run(connect_vars(address, var, remote)).unwrap();
+
+        listener.accept().unwrap();
+    }
+
+    #[test]
+    fn connect_vars_proxy() {
+        let (url, proxy) = proxy("socks5://", vec![
+            (3, vec![5, 0]),
+            (10, SOCKS5_REPLY.to_vec()),
+        ]);
+        let mut reactor = Core::new().unwrap();
+        let remote = reactor.remote();
+        let var = move |name: &str| if name == "ALL_PROXY" { Some(url.clone()) } else { None };
+        reactor.run(connect_vars("127.0.0.1:80", var, remote)).unwrap();
+
+        assert_eq!([5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80], &proxy.join().unwrap()[..]);
+    }
+
+    #[test]
+    fn connect_socks4_resolves_locally() {
+        let (url, proxy) = proxy("socks4://", vec![(9, SOCKS4_REPLY.to_vec())]);
+        run(&url, "localhost:80");
+
+        assert_eq!([4, 1, 0, 80, 127, 0, 0, 1, 0], &proxy.join().unwrap()[..]);
+    }
+
+    #[test]
+    fn connect_socks4a_resolves_remotely() {
+        let (url, proxy) = proxy("socks4a://", vec![(19, SOCKS4_REPLY.to_vec())]);
+        run(&url, "localhost:80");
+
+        let mut expected = vec![4, 1, 0, 80, 0, 0, 0, 1, 0];
+        expected.extend(b"localhost\0");
+        assert_eq!(expected, proxy.join().unwrap());
+    }
+
+    #[test]
+    fn connect_socks4_user_id() {
+        let (url, proxy) = proxy("socks4://joe@", vec![(12, SOCKS4_REPLY.to_vec())]);
+        run(&url, "127.0.0.1:80");
+
+        assert_eq!([4, 1, 0, 80, 127, 0, 0, 1, b'j', b'o', b'e', 0], &proxy.join().unwrap()[..]);
+    }
+
+    #[test]
+    fn connect_socks5_ipv4() {
+        let (url, proxy) = proxy("socks5://", vec![
+            (3, vec![5, 0]),
+            (10, SOCKS5_REPLY.to_vec()),
+        ]);
+        run(&url, "127.0.0.1:80");
+
+        assert_eq!([5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80], &proxy.join().unwrap()[..]);
+    }
+
+    #[test]
+    fn connect_socks5_resolves_locally() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("socks5://{}", listener.local_addr().unwrap());
+        let proxy = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            let mut request = vec![0; 4];
+            stream.read_exact(&mut request).unwrap();
+            // Address is either IPv4 or IPv6, depending on the resolver.
+            let length = if request[3] == 4 { 16 } else { 4 };
+            let mut address = vec![0; length + 2];
+            stream.read_exact(&mut address).unwrap();
+            stream.write_all(&SOCKS5_REPLY).unwrap();
+            request.extend(address);
+            request
+        });
+        run(&url, "localhost:80");
+
+        let request = proxy.join().unwrap();
+        assert_eq!([5, 1, 0], &request[..3]);
+        match request[3] {
+            1 => assert_eq!([1, 127, 0, 0, 1, 0, 80], &request[3..]),
+            4 => assert_eq!([4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80], &request[3..]),
+            atyp => panic!("unexpected address type {}", atyp),
+        }
+    }
+
+    #[test]
+    fn connect_socks5_encoded_credentials() {
+        let (url, proxy) = proxy("socks5://a%40:%3A%FF@", vec![
+            (4, vec![5, 2]),
+            (7, vec![1, 0]),
+            (10, SOCKS5_REPLY.to_vec()),
+        ]);
+        run(&url, "127.0.0.1:80");
+
+        assert_eq!([5, 2, 2, 0,
+                    1, 2, b'a', b'@', 2, b':', 0xff,
+                    5, 1, 0, 1, 127, 0, 0, 1, 0, 80],
+                   &proxy.join().unwrap()[..]);
+    }
+
+    #[test]
+    fn connect_socks5h_resolves_remotely() {
+        let (url, proxy) = proxy("socks5h://", vec![
+            (3, vec![5, 0]),
+            (16, SOCKS5_REPLY.to_vec()),
+        ]);
+        run(&url, "localhost:80");
+
+        let mut expected = vec![5, 1, 0, 5, 1, 0, 3, 9];
+        expected.extend(b"localhost");
+        expected.extend(&[0, 80]);
+        assert_eq!(expected, proxy.join().unwrap());
+    }
+
+    #[test]
+    fn connect_http() {
+        let reply = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        let (url, proxy) = proxy("http://a:b@", vec![(86, reply)]);
+        run(&url, "localhost:80");
+
+        assert_eq!(&b"CONNECT localhost:80 HTTP/1.1\r\nHost: localhost:80\r\n\
+                      Proxy-Authorization: Basic YTpi\r\n\r\n"[..],
+                   &proxy.join().unwrap()[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn connect_socks5_tls() {
+        use native_tls::Pkcs12;
+        use native_tls::TlsAcceptor;
+
+        let identity = Pkcs12::from_der(include_bytes!("../testdata/identity.p12"), "test").unwrap();
+        let acceptor = TlsAcceptor::builder(identity).unwrap().build().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("socks5+tls://{}?ca=testdata/cert.der&sni=localhost", listener.local_addr().unwrap());
+        let proxy = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(stream).unwrap();
+            let mut request = [0; 13];
+            stream.read_exact(&mut request[..3]).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            stream.read_exact(&mut request[3..]).unwrap();
+            stream.write_all(&SOCKS5_REPLY).unwrap();
+            request
+        });
+        run(&url, "127.0.0.1:80");
+
+        assert_eq!([5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80], proxy.join().unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn connect_tls_handshake_failed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("socks5+tls://{}", listener.local_addr().unwrap());
+        let proxy = thread::spawn(move || {
+            // Close the connection without completing the handshake.
+            listener.accept().unwrap();
+        });
+        let mut reactor = Core::new().unwrap();
+        let remote = reactor.remote();
+        let error = reactor.run(connect(&url, "127.0.0.1:80", remote)).err().unwrap();
+        proxy.join().unwrap();
+
+        assert_eq!(Phase::Connect, Error::from_io(&error).unwrap().phase());
+    }
+
+    #[test]
+    #[cfg(not(feature = "tls"))]
+    fn connect_tls_not_enabled() {
+        let mut reactor = Core::new().unwrap();
+        let remote = reactor.remote();
+        let error = reactor.run(connect("socks5+tls://127.0.0.1:1080", "a.com:80", remote)).err().unwrap();
+        assert_eq!("proxy: TLS support is not enabled", format!("{}", error));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn connect_socks5_unix() {
+        use std::collections::hash_map::RandomState;
+        use std::env;
+        use std::fs;
+        use std::hash::BuildHasher;
+        use std::hash::Hasher;
+        use std::os::unix::net::UnixListener;
+
+        // Randomize the name so that concurrent test runs do not collide.
+        let name = format!("socks-connect-unix-{:016x}.sock", RandomState::new().build_hasher().finish());
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let url = format!("socks5+unix://{}", path.display());
+        let proxy = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 13];
+            stream.read_exact(&mut request[..3]).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            stream.read_exact(&mut request[3..]).unwrap();
+            stream.write_all(&SOCKS5_REPLY).unwrap();
+            request
+        });
+        run(&url, "127.0.0.1:80");
+        let _ = fs::remove_file(&path);
+
+        assert_eq!([5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80], proxy.join().unwrap());
+    }
+
+    #[test]
+    fn connect_greeting_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("socks5://{}", listener.local_addr().unwrap());
+        let proxy = thread::spawn(move || {
+            // Never reply and wait for the client to give up.
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+        let timeouts = Timeouts { handshake: Some(Duration::from_millis(50)), .. Timeouts::default() };
+        let mut reactor = Core::new().unwrap();
+        let remote = reactor.remote();
+        let error = reactor.run(connect_timeout(&url, "127.0.0.1:80", &timeouts, remote)).err().unwrap();
+        proxy.join().unwrap();
+
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+        assert_eq!(Phase::Greeting, Error::from_io(&error).unwrap().phase());
+    }
+
+    #[test]
+    fn connect_chain() {
+        let (url, proxy) = proxy("socks5://", vec![
+            (3, vec![5, 0]),
+            (10, SOCKS5_REPLY.to_vec()),
+            (9, SOCKS4_REPLY.to_vec()),
+        ]);
+        run(&format!("{},socks4://10.0.0.1:1080", url), "127.0.0.1:80");
+
+        assert_eq!([5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 4, 56,
+                    4, 1, 0, 80, 127, 0, 0, 1, 0],
+                   &proxy.join().unwrap()[..]);
+    }
+
+    #[test]
+    fn connect_chain_hop_failed() {
+        let (url, proxy) = proxy("socks5://", vec![
+            (3, vec![5, 0]),
+            (10, SOCKS5_REPLY.to_vec()),
+            (9, vec![0, 91, 0, 0, 0, 0, 0, 0]),
+        ]);
+        let url = format!("{},socks4://10.0.0.1:1080", url);
+        let mut reactor = Core::new().unwrap();
+        let remote = reactor.remote();
+        let error = reactor.run(connect(&url, "127.0.0.1:80", remote)).err().unwrap();
+        proxy.join().unwrap();
+
+        match Error::from_io(&error) {
+            Some(&Error::Hop(1, _)) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!("proxy: Request rejected or failed (at hop 1)", format!("{}", error));
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright 2016 Tomasz Miąsko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE>
+// or the MIT license <LICENSE-MIT>, at your option. You may not use
+// this file except according to those terms.
+
+use address::Addr;
+use address::ToAddr;
+use address::match_host;
+use bypass::Cidr;
+use client::ProxyConfig;
+use client::connect_direct;
+use client::connect_proxies;
+use common::*;
+use eyeballs::HappyEyeballs;
+use futures::Future;
+use futures::done;
+use std::fs::File;
+use std::io::Error;
+use std::io::Read;
+use std::io::Result;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use stream::ProxiedStream;
+use tokio_core::io::IoFuture;
+use tokio_core::reactor::Remote;
+use transport::Transport;
+
+/// A client routing connections through different proxies depending on the
+/// destination.
+///
+/// Router is cheap to clone, as clones share the routing table.
+#[derive(Clone)]
+pub struct Router {
+    table: Arc<RoutingTable>,
+    remote: Remote,
+}
+
+impl Router {
+    /// Creates a new router using given routing table.
+    pub fn new(table: RoutingTable, remote: Remote) -> Router {
+        Router {
+            table: Arc::new(table),
+            remote: remote,
+        }
+    }
+
+    /// Returns the routing table of this router.
+    pub fn table(&self) -> &RoutingTable { &self.table }
+
+    /// Creates a new connection to the destination, either directly or
+    /// through a proxy selected by the first matching rule.
+    pub fn connect<D>(&self, destination: D) -> IoFuture<Transport>
+        where D: ToAddr
+    {
+        let table = self.table.clone();
+        let remote = self.remote.clone();
+        done(destination.to_addr()).and_then(move |destination| {
+            match table.route(&destination) {
+                Some(&Route::Proxy(ref config)) => {
+                    connect_proxies(config, destination, remote).map(ProxiedStream::into_inner).boxed()
+                }
+                Some(&Route::Direct) | None => connect_direct(destination, HappyEyeballs::default(), remote),
+            }
+        }).boxed()
+    }
+}
+
+/// Destination of a connection.
+#[derive(Clone)]
+pub enum Route {
+    /// Connect directly, without a proxy.
+    Direct,
+    /// Connect through a proxy.
+    Proxy(ProxyConfig),
+}
+
+/// An ordered list of rules selecting a route for a destination.
+///
+/// Routing table is parsed from text with one rule per line. Each rule
+/// consists of three whitespace-separated columns: host, ports and route.
+/// Empty lines and lines starting with `#` are ignored.
+///
+/// Host is one of:
+///
+///  * `*` - matches all destinations.
+///  * Domain name glob, for example `*.corp`, where `*` matches any sequence
+///    of characters. Matching is case-insensitive.
+///  * CIDR range, for example `10.0.0.0/8`, or a single IP address.
+///
+/// Ports are `*`, a single port, for example `443`, or an inclusive range,
+/// for example `8000-8999`.
+///
+/// Route is either `direct` or a proxy URL, as described in `connect`.
+///
+/// ```text
+/// # host          ports   route
+/// 10.0.0.0/8      *       direct
+/// *.corp          *       socks5://proxy.corp:1080
+/// *               *       socks5h://127.0.0.1:9050
+/// ```
+///
+/// Destination is routed according to the first matching rule, and
+/// connected to directly when no rule matches.
+#[derive(Clone)]
+pub struct RoutingTable {
+    rules: Vec<Rule>,
+}
+
+/// A rule of routing table.
+#[derive(Clone)]
+struct Rule {
+    host: Host,
+    ports: (u16, u16),
+    route: Route,
+}
+
+/// Host matched by a rule.
+#[derive(Clone)]
+enum Host {
+    Any,
+    Domain(String),
+    Cidr(Cidr),
+}
+
+impl RoutingTable {
+    /// Loads a routing table from a file.
+    pub fn from_file<P>(path: P) -> Result<RoutingTable>
+        where P: AsRef<Path>
+    {
+        let mut table = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut table));
+        table.parse()
+    }
+
+    /// Returns the route for given destination, or `None` when no rule
+    /// matches.
+    pub fn route(&self, destination: &Addr) -> Option<&Route> {
+        let port = destination.port();
+        let (domain, ip) = match_host(destination);
+        self.rules.iter().find(|rule| {
+            let host = match rule.host {
+                Host::Any => true,
+                Host::Domain(ref pattern) => domain.as_ref().map_or(false, |domain| glob(pattern.as_bytes(), domain.as_bytes())),
+                Host::Cidr(ref cidr) => ip.as_ref().map_or(false, |ip| cidr.contains(ip)),
+            };
+            host && rule.ports.0 <= port && port <= rule.ports.1
+        }).map(|rule| &rule.route)
+    }
+}
+
+impl FromStr for RoutingTable {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RoutingTable> {
+        let mut rules = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = try!(parse_rule(line).map_err(|err| {
+                invalid_input(format!("{} (on line {})", err, n + 1))
+            }));
+            rules.push(rule);
+        }
+        Ok(RoutingTable { rules: rules })
+    }
+}
+
+/// Parses a single rule of routing table.
+fn parse_rule(line: &str) -> Result<Rule> {
+    let columns: Vec<&str> = line.split_whitespace().collect();
+    if columns.len() != 3 {
+        return Err(invalid_input("proxy: Expected host, ports and route"));
+    }
+    let host = if columns[0] == "*" {
+        Host::Any
+    } else if columns[0].contains('/') {
+        Host::Cidr(try!(columns[0].parse()))
+    } else if let Ok(ip) = IpAddr::from_str(columns[0]) {
+        Host::Cidr(Cidr::host(ip))
+    } else {
+        Host::Domain(columns[0].trim_right_matches('.').to_lowercase())
+    };
+    let ports = try!(parse_ports(columns[1]));
+    let route = if columns[2] == "direct" {
+        Route::Direct
+    } else {
+        Route::Proxy(try!(ProxyConfig::from_url(columns[2])))
+    };
+    Ok(Rule { host: host, ports: ports, route: route })
+}
+
+/// Parses an inclusive range of ports.
+fn parse_ports(ports: &str) -> Result<(u16, u16)> {
+    let invalid = || invalid_input(format!("proxy: Invalid port range {}", ports));
+    if ports == "*" {
+        return Ok((0, 65535));
+    }
+    let (first, last) = match ports.find('-') {
+        Some(i) => (&ports[..i], &ports[i + 1..]),
+        None => (ports, ports),
+    };
+    let first = try!(u16::from_str(first).map_err(|_| invalid()));
+    let last = try!(u16::from_str(last).map_err(|_| invalid()));
+    if first > last {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+/// Returns true if text matches a pattern where `*` matches any sequence of
+/// characters.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((&b'*', rest)) => (0..text.len() + 1).any(|i| glob(rest, &text[i..])),
+        Some((c, rest)) => text.split_first().map_or(false, |(t, text)| c == t && glob(rest, text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Version;
+    use address::*;
+    use common::test::*;
+    use router::*;
+    use std::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    const TABLE: &'static str = "
+        # host          ports       route
+        10.0.0.0/8      *           direct
+        *.corp          22          direct
+        *.corp          *           socks5://proxy.corp:1080
+        ::1             8000-8999   http://localhost:3128
+        *               *           socks5h://127.0.0.1:9050
+    ";
+
+    fn route(table: &RoutingTable, destination: &str) -> Option<Version> {
+        match table.route(&destination.to_addr().unwrap()) {
+            Some(&Route::Proxy(ref config)) => Some(config.version()),
+            Some(&Route::Direct) | None => None,
+        }
+    }
+
+    #[test]
+    fn route_first_match() {
+        let table: RoutingTable = TABLE.parse().unwrap();
+        assert_eq!(None, route(&table, "10.1.2.3:80"));
+        assert_eq!(None, route(&table, "git.corp:22"));
+        assert_eq!(Some(Version::V5), route(&table, "wiki.CORP:443"));
+        assert_eq!(Some(Version::Http), route(&table, "[::1]:8080"));
+        assert_eq!(Some(Version::V5), route(&table, "[::1]:9000"));
+        assert_eq!(Some(Version::V5), route(&table, "corp:80"));
+        assert_eq!(Some(Version::V5), route(&table, "example.com:80"));
+    }
+
+    #[test]
+    fn router_connect() {
+        let (url, proxy) = proxy("socks5://", vec![
+            (3, vec![5, 0]),
+            (10, vec![5, 0, 0, 1, 0, 0, 0, 0, 0, 0]),
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let table = format!("{} {} direct\n* * {}", address.ip(), address.port(), url);
+
+        let mut reactor = Core::new().unwrap();
+        let router = Router::new(table.parse().unwrap(), reactor.remote());
+        reactor.run(router.connect(address)).unwrap();
+        listener.accept().unwrap();
+        reactor.run(router.connect("127.0.0.1:80")).unwrap();
+
+        // The mock proxy accepts a single connection, which is the proxied one.
+        assert_eq!([5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80], &proxy.join().unwrap()[..]);
+    }
+
+    #[test]
+    fn route_no_match() {
+        let table: RoutingTable = "*.corp * socks5://proxy.corp:1080".parse().unwrap();
+        assert!(table.route(&"example.com:80".to_addr().unwrap()).is_none());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("* * direct extra".parse::<RoutingTable>().is_err());
+        assert!("* 10-1 direct".parse::<RoutingTable>().is_err());
+        assert!("10.0.0.0/40 * direct".parse::<RoutingTable>().is_err());
+        let error = "\n* * socks5://joe:secret@".parse::<RoutingTable>().err().unwrap();
+        let message = format!("{}", error);
+        assert!(message.ends_with("(on line 2)"), "{}", message);
+        assert!(!message.contains("secret"), "{}", message);
+    }
+
+    #[test]
+    fn glob_match() {
+        assert!(glob(b"*.corp", b"a.b.corp"));
+        assert!(glob(b"git.*.corp", b"git.eu.corp"));
+        assert!(!glob(b"*.corp", b"corp"));
+        assert!(!glob(b"a*", b"ba"));
+    }
+}
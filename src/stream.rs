@@ -0,0 +1,114 @@
+// Copyright 2016 Tomasz Miąsko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE>
+// or the MIT license <LICENSE-MIT>, at your option. You may not use
+// this file except according to those terms.
+
+use Version;
+use address::Addr;
+use futures::Async;
+use std::io::Read;
+use std::io::Result;
+use std::io::Write;
+use std::ops::Deref;
+use std::ops::DerefMut;
+use tokio_core::io::Io;
+
+/// A stream connected through a proxy.
+///
+/// Dereferences to the underlying stream and carries details of the
+/// handshake performed with the proxy.
+#[derive(Debug)]
+pub struct ProxiedStream<S> {
+    stream: S,
+    version: Version,
+    bound: Addr,
+    auth_method: Option<u8>,
+    proxy: Option<Addr>,
+}
+
+impl<S> ProxiedStream<S> {
+    /// Creates a new proxied stream from the results of a handshake.
+    #[doc(hidden)]
+    pub fn new(stream: S, version: Version, bound: Addr, auth_method: Option<u8>) -> ProxiedStream<S> {
+        ProxiedStream {
+            stream: stream,
+            version: version,
+            bound: bound,
+            auth_method: auth_method,
+            proxy: None,
+        }
+    }
+
+    /// Records the address of the proxy.
+    #[doc(hidden)]
+    pub fn with_proxy(mut self, proxy: Addr) -> ProxiedStream<S> {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Returns the version of protocol used in the handshake.
+    pub fn version(&self) -> Version { self.version }
+
+    /// Returns the address bound by the proxy, as reported in its reply.
+    pub fn bound_addr(&self) -> &Addr { &self.bound }
+
+    /// Returns the authentication method negotiated with the proxy.
+    ///
+    /// Always `None` for SOCKS4 which lacks authentication methods.
+    pub fn auth_method(&self) -> Option<u8> { self.auth_method }
+
+    /// Returns the address of the proxy.
+    ///
+    /// It is `None` when the handshake was performed over an existing stream.
+    pub fn proxy_addr(&self) -> Option<&Addr> { self.proxy.as_ref() }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S { &self.stream }
+
+    /// Returns a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut S { &mut self.stream }
+
+    /// Consumes this wrapper, returning the underlying stream.
+    pub fn into_inner(self) -> S { self.stream }
+}
+
+impl<S> Deref for ProxiedStream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> DerefMut for ProxiedStream<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+impl<S: Read> Read for ProxiedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for ProxiedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: Io> Io for ProxiedStream<S> {
+    fn poll_read(&mut self) -> Async<()> {
+        self.stream.poll_read()
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.stream.poll_write()
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright 2016 Tomasz Miąsko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE>
+// or the MIT license <LICENSE-MIT>, at your option. You may not use
+// this file except according to those terms.
+
+//! TLS transport for connections to a proxy.
+//!
+//! Available with `tls` feature enabled.
+
+use common::*;
+use futures::Future;
+use futures::done;
+use native_tls::Certificate;
+use native_tls::Pkcs12;
+use native_tls::TlsConnector;
+use std::fmt;
+use std::io::Read;
+use std::io::Result;
+use std::io::Write;
+use tokio_core::io::IoFuture;
+use tokio_tls::TlsConnectorExt;
+use tokio_tls::TlsStream;
+
+/// Configuration of TLS session with a proxy.
+///
+/// The identity is left out when formatted for debugging.
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    /// Additional trusted root certificates in DER format.
+    pub ca: Vec<Vec<u8>>,
+    /// Client identity in PKCS #12 format, together with its password.
+    pub identity: Option<(Vec<u8>, String)>,
+    /// Server name used for SNI and certificate validation, instead of the
+    /// proxy host.
+    pub sni: Option<String>,
+}
+
+impl fmt::Debug for TlsOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsOptions")
+            .field("ca", &self.ca.len())
+            .field("identity", &self.identity.as_ref().map(|_| "***"))
+            .field("sni", &self.sni)
+            .finish()
+    }
+}
+
+/// Establishes a TLS session with a proxy over an existing stream.
+///
+/// The domain is the proxy host, used to validate its certificate unless
+/// overridden in options.
+pub fn connect_stream<S>(stream: S, domain: &str, options: &TlsOptions) -> IoFuture<TlsStream<S>>
+    where S: Read + Write + Send + 'static
+{
+    let domain = options.sni.as_ref().map_or(domain, |sni| &sni[..]).to_owned();
+    done(connector(options)).and_then(move |connector| {
+        connector.connect_async(&domain, stream).map_err(other)
+    }).boxed()
+}
+
+/// Builds a connector with given options.
+fn connector(options: &TlsOptions) -> Result<TlsConnector> {
+    let mut builder = try!(TlsConnector::builder().map_err(other));
+    for ca in &options.ca {
+        let certificate = try!(Certificate::from_der(ca).map_err(other));
+        try!(builder.add_root_certificate(certificate).map_err(other));
+    }
+    if let Some((ref der, ref password)) = options.identity {
+        let identity = try!(Pkcs12::from_der(der, password).map_err(other));
+        try!(builder.identity(identity).map_err(other));
+    }
+    builder.build().map_err(other)
+}
+
+#[cfg(test)]
+mod tests {
+    use tls::*;
+
+    #[test]
+    fn debug_masks_identity() {
+        let options = TlsOptions {
+            identity: Some((vec![1, 2, 3], "secret".to_owned())),
+            .. TlsOptions::default()
+        };
+        let debug = format!("{:?}", options);
+        assert!(!debug.contains("secret"), "{}", debug);
+        assert!(debug.contains("***"), "{}", debug);
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2016 Tomasz Miąsko
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE>
+// or the MIT license <LICENSE-MIT>, at your option. You may not use
+// this file except according to those terms.
+
+use futures::Async;
+use std::io::Read;
+use std::io::Result;
+use std::io::Write;
+use tokio_core::io::Io;
+use tokio_core::net::TcpStream;
+#[cfg(feature = "tls")]
+use tokio_tls::TlsStream;
+#[cfg(unix)]
+use tokio_uds::UnixStream;
+
+/// A stream used to communicate with a proxy.
+///
+/// Available variants depend on enabled features and the platform, so new
+/// variants may appear and matches should include a wildcard arm.
+pub enum Transport {
+    /// A TCP connection.
+    Tcp(TcpStream),
+    /// A TLS session over another transport.
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<Transport>>),
+    /// A Unix domain socket connection.
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[doc(hidden)]
+    __Nonexhaustive(Void),
+}
+
+/// A type without values, making the hidden variant of `Transport`
+/// impossible to construct.
+#[doc(hidden)]
+pub enum Void {}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            Transport::Unix(ref mut stream) => stream.read(buf),
+            Transport::__Nonexhaustive(ref void) => match *void {},
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            Transport::Unix(ref mut stream) => stream.write(buf),
+            Transport::__Nonexhaustive(ref void) => match *void {},
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            Transport::Unix(ref mut stream) => stream.flush(),
+            Transport::__Nonexhaustive(ref void) => match *void {},
+        }
+    }
+}
+
+impl Io for Transport {
+    fn poll_read(&mut self) -> Async<()> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.poll_read(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut stream) => stream.poll_read(),
+            #[cfg(unix)]
+            Transport::Unix(ref mut stream) => stream.poll_read(),
+            Transport::__Nonexhaustive(ref void) => match *void {},
+        }
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        match *self {
+            Transport::Tcp(ref mut stream) => stream.poll_write(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(ref mut stream) => stream.poll_write(),
+            #[cfg(unix)]
+            Transport::Unix(ref mut stream) => stream.poll_write(),
+            Transport::__Nonexhaustive(ref void) => match *void {},
+        }
+    }
+}
@@ -6,16 +6,26 @@
 
 //! Implementation of SOCKS4a protocol.
 
+use Version;
 use address::Addr;
 use address::ToAddr;
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
 use common::*;
+use error::Error;
+use error::Phase;
+use error::in_phase;
+use error::protocol;
 use futures::Future;
 use futures::done;
 use self::consts::*;
 use std::io::Read;
 use std::io::Result;
 use std::io::Write;
+use std::net::Ipv4Addr;
 use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use stream::ProxiedStream;
 use tokio_core::io::IoFuture;
 use tokio_core::io::read_exact;
 use tokio_core::io::write_all;
@@ -25,57 +35,134 @@ use tokio_core::reactor::Handle;
 /// Crates a new connection through a SOCKS4a proxy.
 ///
 /// If destination address is provided as a domain name, then hostname is
-/// resolved by proxy.
-pub fn connect<D>(proxy: &SocketAddr, destination: D, handle: &Handle) -> IoFuture<TcpStream>
+/// resolved by proxy. User ID is sent to the proxy to identify the client,
+/// and may be empty.
+pub fn connect<D>(proxy: &SocketAddr, destination: D, user_id: &[u8], handle: &Handle) -> IoFuture<ProxiedStream<TcpStream>>
     where D: ToAddr
 {
     let connection = TcpStream::connect(&proxy, handle);
+    let proxy = *proxy;
+    let user_id = user_id.to_owned();
     Box::new(done(destination.to_addr()).and_then(|address| {
-        connection.and_then(|stream| {
-            connect_stream(stream, address)
+        connection.and_then(move |stream| {
+            connect_stream(stream, address, &user_id)
         })
+    }).and_then(move |stream| {
+        proxy.to_addr().map(|proxy| stream.with_proxy(proxy))
     }))
 }
 
 /// Crates a connection through SOCKS4a proxy using an existing stream.
 #[doc(hidden)]
-pub fn connect_stream<S>(stream: S, destination: Addr) -> IoFuture<S>
+pub fn connect_stream<S>(stream: S, destination: Addr, user_id: &[u8]) -> IoFuture<ProxiedStream<S>>
     where S: Read + Write + Send + 'static
+{
+    send_request(stream, CMD_CONNECT, &destination, user_id).and_then(|stream| {
+        read_reply(stream)
+    }).map(|(bound, stream)| {
+        ProxiedStream::new(stream, Version::V4, bound, None)
+    }).boxed()
+}
+
+/// Requests a SOCKS4 proxy to listen for an incoming connection.
+///
+/// The destination should be the address of a peer that is expected to
+/// connect. Resolves to the address the proxy listens on, which should be
+/// communicated to the peer, together with a future that resolves to the
+/// address of connecting peer and the stream once connection is established.
+pub fn bind<D>(proxy: &SocketAddr, destination: D, user_id: &[u8], handle: &Handle) -> IoFuture<(Addr, IoFuture<(Addr, TcpStream)>)>
+    where D: ToAddr
+{
+    let connection = TcpStream::connect(&proxy, handle);
+    let proxy = *proxy;
+    let user_id = user_id.to_owned();
+    Box::new(done(destination.to_addr()).and_then(|address| {
+        connection.and_then(move |stream| {
+            bind_stream(stream, address, &user_id)
+        })
+    }).map(move |(address, accept)| {
+        // Unspecified address means that proxy address should be used instead.
+        let address = match (address, proxy) {
+            (Addr::V4(ref sa), SocketAddr::V4(ref proxy)) if sa.ip().is_unspecified() => {
+                Addr::V4(SocketAddrV4::new(*proxy.ip(), sa.port()))
+            }
+            (address, _) => address,
+        };
+        (address, accept)
+    }))
+}
+
+/// Requests a SOCKS4 proxy to listen for an incoming connection using an
+/// existing stream.
+#[doc(hidden)]
+pub fn bind_stream<S>(stream: S, destination: Addr, user_id: &[u8]) -> IoFuture<(Addr, IoFuture<(Addr, S)>)>
+    where S: Read + Write + Send + 'static
+{
+    send_request(stream, CMD_BIND, &destination, user_id).and_then(|stream| {
+        // First reply contains the address proxy listens on.
+        read_reply(stream)
+    }).map(|(address, stream)| {
+        // Second reply is sent when the peer connects.
+        (address, read_reply(stream))
+    }).boxed()
+}
+
+/// Sends a request with given command, destination and user ID.
+fn send_request<S>(stream: S, command: u8, destination: &Addr, user_id: &[u8]) -> IoFuture<S>
+    where S: Write + Send + 'static
 {
     done({
         let mut buffer = Vec::new();
-        write_request(&mut buffer, &destination).and(Ok(buffer))
+        write_request(&mut buffer, command, destination, user_id).and(Ok(buffer))
     }).and_then(move |buffer| {
         write_all(stream, buffer)
-    }).and_then(|(stream, mut buffer)| {
-        buffer.resize(8, 0);
-        read_exact(stream, buffer)
-    }).and_then(|(stream, buffer)| {
+    }).map(|(stream, _)| {
+        stream
+    }).map_err(|err| {
+        in_phase(Phase::Request, err)
+    }).boxed()
+}
+
+/// Reads a reply to a request, returning the address contained within.
+fn read_reply<S>(stream: S) -> IoFuture<(Addr, S)>
+    where S: Read + Send + 'static
+{
+    read_exact(stream, vec![0; 8]).and_then(|(stream, buffer)| {
         if buffer[0] != 0 {
-            return Err(invalid_data("proxy: Invalid version in response (not a SOCKS4a proxy?)"))
+            return Err(protocol(Phase::Request, "proxy: Invalid version in response (not a SOCKS4a proxy?)"))
         }
+        // Address follows the status.
+        let ip = Ipv4Addr::new(buffer[4], buffer[5], buffer[6], buffer[7]);
+        let port = BigEndian::read_u16(&buffer[2..4]);
+        let address = Addr::V4(SocketAddrV4::new(ip, port));
         match buffer[1] {
-            90 => Ok(stream),
-            91 => Err(other("proxy: Request rejected or failed")),
-            92 => Err(other("proxy: Request rejected becasue SOCKS server cannot connect to identd on the client")),
-            93 => Err(other("proxy: Request rejected because the client program and identd report different user-ids")),
-            code => Err(other(format!("proxy: Error {}", code))),
+            90 => Ok((address, stream)),
+            91 => Err(Error::Rejected.into()),
+            92 => Err(Error::IdentdUnreachable.into()),
+            93 => Err(Error::IdentdMismatch.into()),
+            code => Err(Error::UnknownStatus(code).into()),
         }
+    }).map_err(|err| {
+        in_phase(Phase::Request, err)
     }).boxed()
 }
 
-/// Writes a connect request to a given buffer.
-fn write_request(buffer: &mut Vec<u8>, destination: &Addr) -> Result<()> {
-    try!(buffer.write(&[VERSION, CMD_CONNECT]));
-    write_address(buffer, destination)
+/// Writes a request to a given buffer.
+fn write_request(buffer: &mut Vec<u8>, command: u8, destination: &Addr, user_id: &[u8]) -> Result<()> {
+    if user_id.contains(&0) {
+        return Err(invalid_input("proxy: user ID contains NUL byte"));
+    }
+    try!(buffer.write(&[VERSION, command]));
+    write_address(buffer, destination, user_id)
 }
 
-/// Writes an address to a given buffer.
-fn write_address(buffer: &mut Vec<u8>, address: &Addr) -> Result<()> {
+/// Writes an address together with user ID to a given buffer.
+fn write_address(buffer: &mut Vec<u8>, address: &Addr, user_id: &[u8]) -> Result<()> {
     match *address {
         Addr::V4(ref sa) => {
             try!(write_port(buffer, sa.port()));
             try!(buffer.write(&sa.ip().octets()));
+            try!(buffer.write(user_id));
             try!(buffer.write(&[0]));
             Ok(())
         }
@@ -88,6 +175,7 @@ fn write_address(buffer: &mut Vec<u8>, address: &Addr) -> Result<()> {
             }
             try!(write_port(buffer, da.port()));
             try!(buffer.write(&[0, 0, 0, 1]));
+            try!(buffer.write(user_id));
             try!(buffer.write(&[0]));
             try!(buffer.write(da.domain().as_bytes()));
             try!(buffer.write(&[0]));
@@ -100,6 +188,7 @@ fn write_address(buffer: &mut Vec<u8>, address: &Addr) -> Result<()> {
 mod consts {
     pub const VERSION: u8 = 4;
     pub const CMD_CONNECT: u8 = 1;
+    pub const CMD_BIND: u8 = 2;
 }
 
 #[cfg(test)]
@@ -124,8 +213,10 @@ mod tests {
 
         let mut reactor = Core::new().unwrap();
         let address = "1.2.3.4:5".to_addr().unwrap();
-        let stream = reactor.run(connect_stream(stream, address)).unwrap();
+        let stream = reactor.run(connect_stream(stream, address, b"")).unwrap();
 
+        assert_eq!("192.168.1.2:2049".to_addr().unwrap(), *stream.bound_addr());
+        assert_eq!(None, stream.auth_method());
         assert_eq!([VERSION, CMD_CONNECT,
                     0, 5,
                     1, 2, 3, 4,
@@ -140,7 +231,7 @@ mod tests {
 
         let mut reactor = Core::new().unwrap();
         let address = "[::ffff:192.168.0.1]:80".to_addr().unwrap();
-        let error = reactor.run(connect_stream(stream, address)).err().unwrap();
+        let error = reactor.run(connect_stream(stream, address, b"")).err().unwrap();
 
         assert_eq!("proxy: IPv6 addresses are unsupported in SOCKS4a", format!("{}", error));
     }
@@ -155,7 +246,7 @@ mod tests {
 
         let mut reactor = Core::new().unwrap();
         let address = "z.com:80".to_addr().unwrap();
-        let stream = reactor.run(connect_stream(stream, address)).unwrap();
+        let stream = reactor.run(connect_stream(stream, address, b"")).unwrap();
 
         assert_eq!([VERSION, CMD_CONNECT,
                     0, 80,
@@ -165,4 +256,61 @@ mod tests {
                     stream.write_buffer());
         assert!(stream.read_all());
     }
+
+    #[test]
+    fn connect_user_id() {
+        let stream = Stream::new(&[
+            RESPONSE_VERSION, REQUEST_GRANTED,
+            0, 0,
+            0, 0, 0, 0,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "1.2.3.4:5".to_addr().unwrap();
+        let stream = reactor.run(connect_stream(stream, address, b"joe")).unwrap();
+
+        assert_eq!([VERSION, CMD_CONNECT,
+                    0, 5,
+                    1, 2, 3, 4,
+                    b'j', b'o', b'e', 0],
+                    stream.write_buffer());
+        assert!(stream.read_all());
+    }
+
+    #[test]
+    fn connect_user_id_with_nul() {
+        let stream = Stream::new(&[]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "1.2.3.4:5".to_addr().unwrap();
+        let error = reactor.run(connect_stream(stream, address, b"a\0b")).err().unwrap();
+
+        assert_eq!("proxy: user ID contains NUL byte", format!("{}", error));
+    }
+
+    #[test]
+    fn bind_ipv4() {
+        let stream = Stream::new(&[
+            RESPONSE_VERSION, REQUEST_GRANTED,
+            8, 1,
+            192, 168, 1, 2,
+            RESPONSE_VERSION, REQUEST_GRANTED,
+            0, 21,
+            10, 0, 0, 1,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "10.0.0.1:0".to_addr().unwrap();
+        let (bound, accept) = reactor.run(bind_stream(stream, address, b"")).unwrap();
+        assert_eq!("192.168.1.2:2049".to_addr().unwrap(), bound);
+
+        let (peer, stream) = reactor.run(accept).unwrap();
+        assert_eq!("10.0.0.1:21".to_addr().unwrap(), peer);
+        assert_eq!([VERSION, CMD_BIND,
+                    0, 0,
+                    10, 0, 0, 1,
+                    0],
+                    stream.write_buffer());
+        assert!(stream.read_all());
+    }
 }
@@ -6,31 +6,50 @@
 
 //! Implementation of SOCKS5 protocol.
 
+use Version;
 use address::Addr;
+use address::Dns;
 use address::DomainAddr;
 use address::ToAddr;
+use address::resolve as resolve_destination;
 use byteorder::BigEndian;
 use byteorder::ByteOrder;
 use common::*;
+use error::Error;
+use error::Phase;
+use error::in_phase;
+use error::protocol;
+use futures::Async;
 use futures::Future;
 use futures::done;
 use futures::failed;
 use futures::finished;
 use self::consts::*;
+use std::cmp;
+use std::collections::hash_map::RandomState;
 use std::convert::TryInto;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
 use std::io::Read;
 use std::io::Result;
 use std::io::Write;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::net::SocketAddrV4;
 use std::net::SocketAddrV6;
 use std::str;
+use std::sync::atomic::ATOMIC_USIZE_INIT;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use stream::ProxiedStream;
 use tokio_core::io::IoFuture;
+use tokio_core::io::flush;
 use tokio_core::io::read_exact;
 use tokio_core::io::write_all;
 use tokio_core::net::TcpStream;
+use tokio_core::net::UdpSocket;
 use tokio_core::reactor::Handle;
 
 /// Authentication method.
@@ -39,131 +58,432 @@ pub enum Auth {
     /// No authentication.
     None,
     /// Authenticate with provided username and password.
-    UserPass(String, String),
+    ///
+    /// Both are sequences of octets as defined in RFC 1929, and need not be
+    /// valid UTF-8.
+    UserPass(Vec<u8>, Vec<u8>),
+}
+
+/// A trait for SOCKS5 authentication methods.
+///
+/// An authenticator declares identifiers of methods it supports, which are
+/// offered to the proxy during negotiation, and performs method specific
+/// sub-negotiation once the proxy selects one of them.
+///
+/// By default is implemented for the following types:
+///
+///  * `Auth` - built-in methods without authentication and with username and
+///    password.
+///
+///  * `Vec<A>` - offers methods of all authenticators in order and delegates
+///    to the first one supporting selected method.
+///
+///  * `(A, B)` - same as above, useful for combining different types of
+///    authenticators.
+///
+pub trait Authenticator: Send + 'static {
+    /// Returns identifiers of supported methods in order of preference.
+    fn methods(&self) -> Vec<u8>;
+
+    /// Performs sub-negotiation of a method selected by the proxy.
+    fn authenticate<S>(&self, method: u8, stream: S) -> IoFuture<S>
+        where S: Read + Write + Send + 'static;
+}
+
+impl Authenticator for Auth {
+    fn methods(&self) -> Vec<u8> {
+        match *self {
+            Auth::None => vec![AUTH_NONE],
+            Auth::UserPass(..) => vec![AUTH_USER_PASS],
+        }
+    }
+
+    fn authenticate<S>(&self, _: u8, stream: S) -> IoFuture<S>
+        where S: Read + Write + Send + 'static
+    {
+        match *self {
+            Auth::None => finished(stream).boxed(),
+            Auth::UserPass(ref user, ref pass) => authenticate_user_pass(stream, user, pass),
+        }
+    }
+}
+
+impl<A: Authenticator> Authenticator for Vec<A> {
+    fn methods(&self) -> Vec<u8> {
+        self.iter().flat_map(|auth| auth.methods()).collect()
+    }
+
+    fn authenticate<S>(&self, method: u8, stream: S) -> IoFuture<S>
+        where S: Read + Write + Send + 'static
+    {
+        match self.iter().find(|auth| auth.methods().contains(&method)) {
+            Some(auth) => auth.authenticate(method, stream),
+            None => failed(protocol(Phase::Greeting, "proxy: Server selected an invalid authentication method")).boxed(),
+        }
+    }
+}
+
+impl<A: Authenticator, B: Authenticator> Authenticator for (A, B) {
+    fn methods(&self) -> Vec<u8> {
+        let mut methods = self.0.methods();
+        methods.extend(self.1.methods());
+        methods
+    }
+
+    fn authenticate<S>(&self, method: u8, stream: S) -> IoFuture<S>
+        where S: Read + Write + Send + 'static
+    {
+        if self.0.methods().contains(&method) {
+            self.0.authenticate(method, stream)
+        } else {
+            self.1.authenticate(method, stream)
+        }
+    }
+}
+
+/// Stream isolation for Tor.
+///
+/// Tor isolates streams that use different SOCKS username and password into
+/// separate circuits. Isolation authenticates with username and password
+/// derived from an isolation key, so that connections using the same key share
+/// a circuit, while connections using different keys do not.
+///
+/// Only username and password method is offered. To also allow proxies that
+/// do not support authentication combine it with `Auth::None`, for example
+/// `(Isolation::Random, Auth::None)`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Isolation {
+    /// Isolates connections by provided key.
+    Key(String),
+    /// Isolates each connection using a newly generated random key.
+    Random,
+}
+
+impl Isolation {
+    /// Returns credentials used for the next connection.
+    #[doc(hidden)]
+    pub fn credentials(&self) -> Auth {
+        let key = match *self {
+            Isolation::Key(ref key) => key.clone(),
+            Isolation::Random => random_key(),
+        };
+        Auth::UserPass(key.clone().into_bytes(), key.into_bytes())
+    }
+}
+
+impl Authenticator for Isolation {
+    fn methods(&self) -> Vec<u8> {
+        vec![AUTH_USER_PASS]
+    }
+
+    fn authenticate<S>(&self, method: u8, stream: S) -> IoFuture<S>
+        where S: Read + Write + Send + 'static
+    {
+        self.credentials().authenticate(method, stream)
+    }
+}
+
+/// Generates a new key.
+///
+/// Keys include a counter, so they never repeat within a process, and a
+/// randomly seeded hash, so they are unlikely to repeat across processes.
+fn random_key() -> String {
+    static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_usize(counter);
+    format!("{:016x}-{:x}", hasher.finish(), counter)
 }
 
 /// Crates a new connection through a SOCKS5 proxy.
 ///
 /// If destination address is provided as a domain name, then hostname is
-/// resolved by proxy.
-pub fn connect<D>(proxy: &SocketAddr, destination: D, auth: Auth, handle: &Handle) -> IoFuture<TcpStream>
-    where D: ToAddr
+/// resolved according to DNS policy, either locally or by proxy.
+/// Authentication methods are offered to the proxy in given order of
+/// preference, and the one selected by the proxy is used.
+pub fn connect<D, A>(proxy: &SocketAddr, destination: D, auth: A, dns: Dns, handle: &Handle) -> IoFuture<ProxiedStream<TcpStream>>
+    where D: ToAddr, A: Authenticator
 {
     let connection = TcpStream::connect(&proxy, handle);
-    Box::new(done(destination.to_addr()).and_then(|address| {
+    let proxy = *proxy;
+    Box::new(done(destination.to_addr()).and_then(move |address| {
+        resolve_destination(address, dns)
+    }).and_then(|address| {
         connection.and_then(|stream| {
             connect_stream(stream, address, auth)
         })
+    }).and_then(move |stream| {
+        proxy.to_addr().map(|proxy| stream.with_proxy(proxy))
     }))
 }
 
 /// Crates a new connection through SOCKS5 proxy using an existing stream.
 #[doc(hidden)]
-pub fn connect_stream<S>(stream: S, destination: Addr, auth: Auth) -> IoFuture<S>
+pub fn connect_stream<S, A>(stream: S, destination: Addr, auth: A) -> IoFuture<ProxiedStream<S>>
+    where S: Read + Write + Send + 'static, A: Authenticator
+{
+    negotiate(stream, auth).and_then(move |(stream, method)| {
+        connect_negotiated(stream, destination, method)
+    }).boxed()
+}
+
+/// Sends a connect request over a stream on which negotiation with given
+/// authentication method has already been completed.
+#[doc(hidden)]
+pub fn connect_negotiated<S>(stream: S, destination: Addr, method: u8) -> IoFuture<ProxiedStream<S>>
     where S: Read + Write + Send + 'static
 {
-    let auth_method = match auth {
-        Auth::None => AUTH_NONE,
-        Auth::UserPass(..) => AUTH_USER_PASS,
+    write_request(stream, CMD_CONNECT, &destination).and_then(|stream| {
+        read_reply(stream)
+    }).map(move |(bound, stream)| {
+        ProxiedStream::new(stream, Version::V5, bound, Some(method))
+    }).boxed()
+}
+
+/// Requests a SOCKS5 proxy to listen for an incoming connection.
+///
+/// The destination should be the address of a peer that is expected to
+/// connect. Resolves to the address the proxy listens on, which should be
+/// communicated to the peer, together with a future that resolves to the
+/// address of connecting peer and the stream once connection is established.
+pub fn bind<D, A>(proxy: &SocketAddr, destination: D, auth: A, handle: &Handle) -> IoFuture<(Addr, IoFuture<(Addr, TcpStream)>)>
+    where D: ToAddr, A: Authenticator
+{
+    let connection = TcpStream::connect(&proxy, handle);
+    let proxy = *proxy;
+    Box::new(done(destination.to_addr()).and_then(|address| {
+        connection.and_then(|stream| {
+            bind_stream(stream, address, auth)
+        })
+    }).map(move |(address, accept)| {
+        // Unspecified address means that proxy address should be used instead.
+        let address = match (address, proxy) {
+            (Addr::V4(ref sa), SocketAddr::V4(ref proxy)) if sa.ip().is_unspecified() => {
+                Addr::V4(SocketAddrV4::new(*proxy.ip(), sa.port()))
+            }
+            (Addr::V6(ref sa), SocketAddr::V6(ref proxy)) if sa.ip().is_unspecified() => {
+                Addr::V6(SocketAddrV6::new(*proxy.ip(), sa.port(), 0, 0))
+            }
+            (address, _) => address,
+        };
+        (address, accept)
+    }))
+}
+
+/// Requests a SOCKS5 proxy to listen for an incoming connection using an
+/// existing stream.
+#[doc(hidden)]
+pub fn bind_stream<S, A>(stream: S, destination: Addr, auth: A) -> IoFuture<(Addr, IoFuture<(Addr, S)>)>
+    where S: Read + Write + Send + 'static, A: Authenticator
+{
+    negotiate(stream, auth).and_then(move |(stream, _)| {
+        write_request(stream, CMD_BIND, &destination)
+    }).and_then(|stream| {
+        // First reply contains the address proxy listens on.
+        read_reply(stream)
+    }).map(|(address, stream)| {
+        // Second reply is sent when the peer connects.
+        (address, read_reply(stream))
+    }).boxed()
+}
+
+/// Resolves a domain name to an IP address using a proxy.
+///
+/// Uses RESOLVE command, which is an extension of SOCKS5 protocol supported
+/// by Tor.
+pub fn resolve<A>(proxy: &SocketAddr, domain: &str, auth: A, handle: &Handle) -> IoFuture<IpAddr>
+    where A: Authenticator
+{
+    let connection = TcpStream::connect(&proxy, handle);
+    let domain = domain.to_owned();
+    connection.and_then(move |stream| {
+        resolve_stream(stream, &domain, auth)
+    }).boxed()
+}
+
+/// Resolves a domain name to an IP address using a proxy over an existing
+/// stream.
+#[doc(hidden)]
+pub fn resolve_stream<S, A>(stream: S, domain: &str, auth: A) -> IoFuture<IpAddr>
+    where S: Read + Write + Send + 'static, A: Authenticator
+{
+    let domain = Addr::Domain(DomainAddr::new(domain, 0));
+    negotiate(stream, auth).and_then(move |(stream, _)| {
+        write_request(stream, CMD_RESOLVE, &domain)
+    }).and_then(|stream| {
+        read_reply(stream)
+    }).and_then(|(address, _)| {
+        match address {
+            Addr::V4(sa) => Ok(IpAddr::V4(*sa.ip())),
+            Addr::V6(sa) => Ok(IpAddr::V6(*sa.ip())),
+            Addr::Domain(..) => Err(protocol(Phase::Request, "proxy: received domain name instead of IP address")),
+        }
+    }).boxed()
+}
+
+/// Resolves an IP address to a domain name using a proxy.
+///
+/// Uses RESOLVE_PTR command, which is an extension of SOCKS5 protocol
+/// supported by Tor.
+pub fn resolve_ptr<A>(proxy: &SocketAddr, ip: IpAddr, auth: A, handle: &Handle) -> IoFuture<String>
+    where A: Authenticator
+{
+    TcpStream::connect(&proxy, handle).and_then(move |stream| {
+        resolve_ptr_stream(stream, ip, auth)
+    }).boxed()
+}
+
+/// Resolves an IP address to a domain name using a proxy over an existing
+/// stream.
+#[doc(hidden)]
+pub fn resolve_ptr_stream<S, A>(stream: S, ip: IpAddr, auth: A) -> IoFuture<String>
+    where S: Read + Write + Send + 'static, A: Authenticator
+{
+    let address = match ip {
+        IpAddr::V4(ip) => Addr::V4(SocketAddrV4::new(ip, 0)),
+        IpAddr::V6(ip) => Addr::V6(SocketAddrV6::new(ip, 0, 0, 0)),
     };
+    negotiate(stream, auth).and_then(move |(stream, _)| {
+        write_request(stream, CMD_RESOLVE_PTR, &address)
+    }).and_then(|stream| {
+        read_reply(stream)
+    }).and_then(|(address, _)| {
+        match address {
+            Addr::Domain(da) => Ok(da.domain().to_owned()),
+            _ => Err(protocol(Phase::Request, "proxy: received IP address instead of domain name")),
+        }
+    }).boxed()
+}
 
-    Box::new(
-        // Send socks version and selected authentication method.
-        write_all(stream, vec![VERSION, 1, auth_method]
-    ).and_then(|(stream, mut buff)| {
+/// Sends version identifier and method selection message, then performs
+/// method specific sub-negotiation. Resolves to the stream and the selected
+/// method.
+#[doc(hidden)]
+pub fn negotiate<S, A>(stream: S, auth: A) -> IoFuture<(S, u8)>
+    where S: Read + Write + Send + 'static, A: Authenticator
+{
+    let methods = auth.methods();
+    done((|| {
+        if methods.is_empty() {
+            return Err(invalid_input("proxy: No authentication methods provided"));
+        }
+        let count = try!(methods.len().try_into().map_err(|_| {
+            invalid_input("proxy: Too many authentication methods")
+        }));
+        let mut buff = vec![VERSION, count];
+        buff.extend(&methods);
+        Ok(buff)
+    })()).and_then(|buff| {
+        // Send socks version and offered authentication methods.
+        write_all(stream, buff)
+    }).and_then(|(stream, mut buff)| {
         // Receive server version and selected authentication method.
         buff.resize(2, 0);
         read_exact(stream, buff)
     }).and_then(move |(stream, buff)| {
         // Parse and validate authentication method.
         if buff[0] != VERSION {
-            return Err(invalid_data("proxy: Invalid version in response (not a SOCKS5 proxy?)"))
+            return Err(protocol(Phase::Greeting, "proxy: Invalid version in response (not a SOCKS5 proxy?)"))
         }
         if buff[1] == AUTH_NO_ACCEPTABLE {
-            return Err(other("proxy: No acceptable authentication methods"))
+            return Err(Error::NoAcceptableMethods.into())
         }
-        if buff[1] != auth_method {
-            return Err(invalid_data("proxy: Server selected an invalid authentication method"))
-        } 
-        Ok((stream, buff))
-    }).and_then(|(stream, buff)| {
-        authenticate(stream, buff, auth)
-    }).and_then(move |(stream, mut buff)| {
-        // Prepare connect request.
-        buff.clear();
-        buff.extend(&[VERSION, CMD_CONNECT, RESERVED]);
-        write_address(&mut buff, &destination).and(Ok((stream, buff)))
-    }).and_then(|(stream, buff)| {
-        // Send connect request
+        if !methods.contains(&buff[1]) {
+            return Err(protocol(Phase::Greeting, "proxy: Server selected an invalid authentication method"))
+        }
+        Ok((stream, buff[1]))
+    }).map_err(|err| {
+        in_phase(Phase::Greeting, err)
+    }).and_then(move |(stream, method)| {
+        auth.authenticate(method, stream).map(move |stream| {
+            (stream, method)
+        }).map_err(|err| {
+            in_phase(Phase::Authentication, err)
+        })
+    }).boxed()
+}
+
+/// Sends a request with given command and address.
+fn write_request<S>(stream: S, command: u8, address: &Addr) -> IoFuture<S>
+    where S: Write + Send + 'static
+{
+    done({
+        let mut buff = vec![VERSION, command, RESERVED];
+        write_address(&mut buff, address).and(Ok(buff))
+    }).and_then(|buff| {
         write_all(stream, buff)
-    }).and_then(|(stream, mut buff)| {
-        // Read reply up to variable length address.
-        buff.resize(4, 0);
-        read_exact(stream, buff)
-    }).and_then(|(stream, buff)| {
-        // Parse and validate reply to connect request.
+    }).and_then(|(stream, _)| {
+        flush(stream)
+    }).map_err(|err| {
+        in_phase(Phase::Request, err)
+    }).boxed()
+}
+
+/// Reads a reply to a request, returning the address contained within.
+fn read_reply<S>(stream: S) -> IoFuture<(Addr, S)>
+    where S: Read + Send + 'static
+{
+    // Read reply up to variable length address.
+    read_exact(stream, vec![0; 4]).and_then(|(stream, buff)| {
+        // Parse and validate reply.
         if buff[0] != VERSION {
-            return Err(invalid_data("proxy: received invalid version in response"));
+            return Err(protocol(Phase::Request, "proxy: received invalid version in response"));
         }
         if buff[2] != RESERVED {
-            return Err(invalid_data("proxy: received invalid non-zero reserved field"))
+            return Err(protocol(Phase::Request, "proxy: received invalid non-zero reserved field"))
         }
         match buff[1] {
             0 => Ok((stream, buff)),
-            1 => Err(other("proxy: General SOCKS server failure")),
-            2 => Err(other("proxy: Connection not allowed by ruleset")),
-            3 => Err(other("proxy: Network unreachable")),
-            4 => Err(other("proxy: Host unreachable")),
-            5 => Err(other("proxy: Connection refused")),
-            6 => Err(other("proxy: TTL expired")),
-            7 => Err(other("proxy: Command not supported")),
-            8 => Err(other("proxy: Address type not supported")),
-            code => Err(other(format!("proxy: Error {}", code))),
+            1 => Err(Error::GeneralFailure.into()),
+            2 => Err(Error::NotAllowed.into()),
+            3 => Err(Error::NetworkUnreachable.into()),
+            4 => Err(Error::HostUnreachable.into()),
+            5 => Err(Error::ConnectionRefused.into()),
+            6 => Err(Error::TtlExpired.into()),
+            7 => Err(Error::CommandNotSupported.into()),
+            8 => Err(Error::AddressTypeNotSupported.into()),
+            code => Err(Error::UnknownReply(code).into()),
         }
     }).and_then(|(stream, buff)| {
-        // Read address from response.
+        // Read address from reply.
         match buff[3] {
             ATYP_IPV4 => read_ipv4_address(stream, buff),
             ATYP_IPV6 => read_ipv6_address(stream, buff),
             ATYP_DOMAIN_NAME => read_domain_address(stream, buff),
-            _ => Box::new(failed(other(format!("proxy: Unsupported address type {}", buff[3])))),
+            _ => Box::new(failed(protocol(Phase::Request, format!("proxy: Unsupported address type {}", buff[3])))),
         }
-    }).map(|(_, stream)| {
-        stream
-    }))
+    }).map_err(|err| {
+        in_phase(Phase::Request, err)
+    }).boxed()
 }
 
-fn authenticate<S>(stream: S, mut buffer: Vec<u8>, auth: Auth) -> IoFuture<(S, Vec<u8>)>
+/// Performs username and password sub-negotiation as described in RFC 1929.
+fn authenticate_user_pass<S>(stream: S, user: &[u8], pass: &[u8]) -> IoFuture<S>
     where S: Read + Write + Send + 'static
 {
-    match auth {
-        Auth::None => finished((stream, buffer)).boxed(),
-        Auth::UserPass(ref user, ref pass) => {
-            done((|| {
-                let user_len = try!(user.len().try_into().map_err(|_| invalid_input("proxy: Username length exceeds 255 bytes")));
-                let pass_len = try!(pass.len().try_into().map_err(|_| invalid_input("proxy: Password length exceeds 255 bytes")));
-                buffer.clear();
-                try!(buffer.write(&[AUTH_USER_PASS_VERSION, user_len]));
-                try!(buffer.write(user.as_bytes()));
-                try!(buffer.write(&[pass_len]));
-                try!(buffer.write(pass.as_bytes()));
-                Ok(buffer)
-            })()).and_then(|buffer| {
-                write_all(stream, buffer)
-            }).and_then(|(stream, mut buffer)| {
-                buffer.resize(2, 0);
-                read_exact(stream, buffer)
-            }).and_then(|(stream, buffer)| {
-                if buffer[0] != AUTH_USER_PASS_VERSION {
-                    return Err(invalid_data("proxy: Invalid authentication version in response"))
-                }
-                if buffer[1] != AUTH_SUCCEEDED {
-                    return Err(other("proxy: Authentication failure"))
-                }
-                Ok((stream, buffer))
-            }).boxed()
+    done((|| {
+        let user_len = try!(user.len().try_into().map_err(|_| invalid_input("proxy: Username length exceeds 255 bytes")));
+        let pass_len = try!(pass.len().try_into().map_err(|_| invalid_input("proxy: Password length exceeds 255 bytes")));
+        let mut buffer = Vec::new();
+        try!(buffer.write(&[AUTH_USER_PASS_VERSION, user_len]));
+        try!(buffer.write(user));
+        try!(buffer.write(&[pass_len]));
+        try!(buffer.write(pass));
+        Ok(buffer)
+    })()).and_then(|buffer| {
+        write_all(stream, buffer)
+    }).and_then(|(stream, mut buffer)| {
+        buffer.resize(2, 0);
+        read_exact(stream, buffer)
+    }).and_then(|(stream, buffer)| {
+        if buffer[0] != AUTH_USER_PASS_VERSION {
+            return Err(protocol(Phase::Authentication, "proxy: Invalid authentication version in response"))
         }
-    }
+        if buffer[1] != AUTH_SUCCEEDED {
+            return Err(Error::AuthenticationFailed.into())
+        }
+        Ok(stream)
+    }).boxed()
 }
 
 fn write_address(buffer: &mut Vec<u8>, address: &Addr) -> Result<()> {
@@ -201,10 +521,7 @@ fn read_ipv4_address<S>(stream: S, mut buff: Vec<u8>) -> IoFuture<(Addr, S)>
     // Read IPv4 address and port.
     buff.resize(6, 0);
     read_exact(stream, buff).map(|(stream, buff)| {
-        // Parse IPv4 address and port.
-        let ip = Ipv4Addr::new(buff[0], buff[1], buff[2], buff[3]);
-        let port = BigEndian::read_u16(&buff[4..6]);
-        (Addr::V4(SocketAddrV4::new(ip, port)), stream)
+        (parse_ipv4_address(&buff), stream)
     }).boxed()
 }
 
@@ -214,18 +531,7 @@ fn read_ipv6_address<S>(stream: S, mut buff: Vec<u8>) -> IoFuture<(Addr, S)>
     // Read IPv6 address and port.
     buff.resize(18, 0);
     read_exact(stream, buff).map(|(stream, buff)| {
-        // Parse IPv6 address and port.
-        let ip = Ipv6Addr::new(
-            BigEndian::read_u16(&buff[0..2]),
-            BigEndian::read_u16(&buff[2..4]),
-            BigEndian::read_u16(&buff[4..6]),
-            BigEndian::read_u16(&buff[6..8]),
-            BigEndian::read_u16(&buff[8..10]),
-            BigEndian::read_u16(&buff[10..12]),
-            BigEndian::read_u16(&buff[12..14]),
-            BigEndian::read_u16(&buff[14..16]));
-        let port = BigEndian::read_u16(&buff[16..18]);
-        (Addr::V6(SocketAddrV6::new(ip, port, 0, 0)), stream)
+        (parse_ipv6_address(&buff), stream)
     }).boxed()
 }
 
@@ -240,17 +546,199 @@ fn read_domain_address<S>(stream: S, mut buff: Vec<u8>) -> IoFuture<(Addr, S)>
         buff.resize(domain_length, 0);
         read_exact(stream, buff)
     }).and_then(|(stream, buff)| {
-        // Parse domain name and port
-        let domain_length = buff.len() - 2;
-        str::from_utf8(&buff[0..domain_length]).map_err(|_| {
-            invalid_data("proxy: received invalid domain name")
-        }).map(|domain| {
-            let port = BigEndian::read_u16(&buff[domain_length..]);
-            (Addr::Domain(DomainAddr::new(domain, port)), stream)
+        parse_domain_address(&buff).map(|address| (address, stream))
+    }).boxed()
+}
+
+/// Parses IPv4 address followed by a port.
+fn parse_ipv4_address(buff: &[u8]) -> Addr {
+    let ip = Ipv4Addr::new(buff[0], buff[1], buff[2], buff[3]);
+    let port = BigEndian::read_u16(&buff[4..6]);
+    Addr::V4(SocketAddrV4::new(ip, port))
+}
+
+/// Parses IPv6 address followed by a port.
+fn parse_ipv6_address(buff: &[u8]) -> Addr {
+    let ip = Ipv6Addr::new(
+        BigEndian::read_u16(&buff[0..2]),
+        BigEndian::read_u16(&buff[2..4]),
+        BigEndian::read_u16(&buff[4..6]),
+        BigEndian::read_u16(&buff[6..8]),
+        BigEndian::read_u16(&buff[8..10]),
+        BigEndian::read_u16(&buff[10..12]),
+        BigEndian::read_u16(&buff[12..14]),
+        BigEndian::read_u16(&buff[14..16]));
+    let port = BigEndian::read_u16(&buff[16..18]);
+    Addr::V6(SocketAddrV6::new(ip, port, 0, 0))
+}
+
+/// Parses domain name followed by a port.
+fn parse_domain_address(buff: &[u8]) -> Result<Addr> {
+    let domain_length = buff.len() - 2;
+    let domain = try!(str::from_utf8(&buff[0..domain_length]).map_err(|_| {
+        protocol(Phase::Request, "proxy: received invalid domain name")
+    }));
+    let port = BigEndian::read_u16(&buff[domain_length..]);
+    Ok(Addr::Domain(DomainAddr::new(domain, port)))
+}
+
+/// Associates a UDP socket with a SOCKS5 proxy using UDP ASSOCIATE command.
+///
+/// The association lasts as long as the control connection to the proxy,
+/// which is owned by returned `UdpAssociation`. Dropping it terminates the
+/// association.
+pub fn associate<A>(proxy: &SocketAddr, auth: A, handle: &Handle) -> IoFuture<UdpAssociation<TcpStream>>
+    where A: Authenticator
+{
+    let local = match *proxy {
+        SocketAddr::V4(..) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
+        SocketAddr::V6(..) => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0, 0, 0)),
+    };
+    let socket = match UdpSocket::bind(&local, handle) {
+        Ok(socket) => socket,
+        Err(err) => return failed(err).boxed(),
+    };
+    let proxy_ip = proxy.ip();
+    TcpStream::connect(&proxy, handle).and_then(move |stream| {
+        associate_stream(stream, socket, proxy_ip, auth)
+    }).boxed()
+}
+
+/// Associates a UDP socket with a SOCKS5 proxy using an existing control
+/// stream.
+///
+/// Proxy IP address is used in place of unspecified relay address in the reply.
+#[doc(hidden)]
+pub fn associate_stream<S, A>(stream: S, socket: UdpSocket, proxy: IpAddr, auth: A) -> IoFuture<UdpAssociation<S>>
+    where S: Read + Write + Send + 'static, A: Authenticator
+{
+    done(socket.local_addr().and_then(|local| local.to_addr())).and_then(move |local| {
+        negotiate(stream, auth).and_then(move |(stream, _)| {
+            write_request(stream, CMD_UDP_ASSOCIATE, &local)
+        })
+    }).and_then(|stream| {
+        read_reply(stream)
+    }).and_then(move |(relay, stream)| {
+        let relay = match relay {
+            Addr::V4(sa) if sa.ip().is_unspecified() => SocketAddr::new(proxy, sa.port()),
+            Addr::V6(sa) if sa.ip().is_unspecified() => SocketAddr::new(proxy, sa.port()),
+            Addr::V4(sa) => SocketAddr::V4(sa),
+            Addr::V6(sa) => SocketAddr::V6(sa),
+            Addr::Domain(da) => {
+                return Err(protocol(Phase::Request, format!("proxy: Unsupported relay address {}", da)))
+            }
+        };
+        Ok(UdpAssociation {
+            control: stream,
+            socket: socket,
+            relay: relay,
         })
     }).boxed()
 }
 
+/// A UDP socket associated with a SOCKS5 proxy.
+///
+/// Datagrams are sent to and received from the proxy relay, encapsulated with
+/// a header describing their actual destination or source.
+pub struct UdpAssociation<S> {
+    control: S,
+    socket: UdpSocket,
+    relay: SocketAddr,
+}
+
+impl<S> UdpAssociation<S> {
+    /// Returns the address of the proxy relay.
+    pub fn relay_addr(&self) -> &SocketAddr {
+        &self.relay
+    }
+
+    /// Returns the local address of the underlying UDP socket.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Returns the control stream.
+    pub fn control(&self) -> &S {
+        &self.control
+    }
+
+    /// Tests whether this socket is ready to be read.
+    pub fn poll_read(&self) -> Async<()> {
+        self.socket.poll_read()
+    }
+
+    /// Tests whether this socket is ready to be written to.
+    pub fn poll_write(&self) -> Async<()> {
+        self.socket.poll_write()
+    }
+
+    /// Sends data to the given address through the proxy relay.
+    ///
+    /// On success returns the number of bytes of data sent.
+    pub fn send_to(&self, buf: &[u8], target: &Addr) -> Result<usize> {
+        let datagram = try!(encapsulate(target, buf));
+        try!(self.socket.send_to(&datagram, &self.relay));
+        Ok(buf.len())
+    }
+
+    /// Receives data from the proxy relay.
+    ///
+    /// On success returns the number of bytes read and the address data
+    /// originated from. Excess data that does not fit into the buffer is
+    /// discarded. Datagrams that do not come from the relay, are malformed or
+    /// fragmented are silently dropped.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Addr)> {
+        let mut datagram = vec![0; 65536];
+        loop {
+            let (n, source) = try!(self.socket.recv_from(&mut datagram));
+            if source != self.relay {
+                continue;
+            }
+            if let Ok(Some((address, data))) = decapsulate(&datagram[..n]) {
+                let n = cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                return Ok((n, address));
+            }
+        }
+    }
+}
+
+/// Prepends UDP request header to the data.
+fn encapsulate(address: &Addr, data: &[u8]) -> Result<Vec<u8>> {
+    let mut datagram = vec![RESERVED, RESERVED, 0];
+    try!(write_address(&mut datagram, address));
+    try!(datagram.write(data));
+    Ok(datagram)
+}
+
+/// Parses UDP request header returning the address and data.
+///
+/// Returns `None` for fragmented datagrams, which are unsupported.
+fn decapsulate(datagram: &[u8]) -> Result<Option<(Addr, &[u8])>> {
+    let truncated = || invalid_data("proxy: received truncated datagram");
+    if datagram.len() < 4 {
+        return Err(truncated());
+    }
+    if datagram[0] != RESERVED || datagram[1] != RESERVED {
+        return Err(invalid_data("proxy: received invalid non-zero reserved field"));
+    }
+    if datagram[2] != 0 {
+        return Ok(None);
+    }
+    let buff = &datagram[4..];
+    let (address, length) = match datagram[3] {
+        ATYP_IPV4 if buff.len() >= 6 => (parse_ipv4_address(buff), 6),
+        ATYP_IPV6 if buff.len() >= 18 => (parse_ipv6_address(buff), 18),
+        ATYP_DOMAIN_NAME if !buff.is_empty() && buff.len() >= usize::from(buff[0]) + 3 => {
+            let length = usize::from(buff[0]) + 3;
+            (try!(parse_domain_address(&buff[1..length])), length)
+        }
+        ATYP_IPV4 | ATYP_IPV6 | ATYP_DOMAIN_NAME => return Err(truncated()),
+        atyp => return Err(other(format!("proxy: Unsupported address type {}", atyp))),
+    };
+    Ok(Some((address, &buff[length..])))
+}
+
 /// Constants used in SOCKS version 5.
 mod consts {
     pub const VERSION: u8 = 5;
@@ -260,6 +748,10 @@ mod consts {
     pub const AUTH_SUCCEEDED: u8 = 0;
     pub const AUTH_NO_ACCEPTABLE: u8 = 255;
     pub const CMD_CONNECT: u8 = 1;
+    pub const CMD_BIND: u8 = 2;
+    pub const CMD_UDP_ASSOCIATE: u8 = 3;
+    pub const CMD_RESOLVE: u8 = 0xf0;
+    pub const CMD_RESOLVE_PTR: u8 = 0xf1;
     pub const RESERVED: u8 = 0;
     pub const ATYP_IPV4: u8 = 1;
     pub const ATYP_IPV6: u8 = 4;
@@ -268,8 +760,23 @@ mod consts {
 
 #[cfg(test)]
 mod tests {
+    use Version;
     use address::*;
+    use common::*;
     use common::test::*;
+    use error::Error;
+    use error::Phase;
+    use futures::Future;
+    use std::io::ErrorKind;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::IpAddr;
+    use std::net::Ipv4Addr;
+    use std::net::SocketAddr;
+    use tokio_core::io::IoFuture;
+    use tokio_core::io::read_exact;
+    use tokio_core::io::write_all;
+    use tokio_core::net::UdpSocket;
     use tokio_core::reactor::Core;
     use v5::*;
     use v5::consts::*;
@@ -286,8 +793,11 @@ mod tests {
         ]);
         let mut reactor = Core::new().unwrap();
         let address = "1.2.3.4:5".to_addr().unwrap();
-        let stream = reactor.run(connect_stream(stream, address, Auth::None)).unwrap();
+        let stream = reactor.run(connect_stream(stream, address, vec![Auth::None])).unwrap();
 
+        assert_eq!("192.168.1.2:2049".to_addr().unwrap(), *stream.bound_addr());
+        assert_eq!(Version::V5, stream.version());
+        assert_eq!(Some(AUTH_NONE), stream.auth_method());
         assert_eq!([VERSION, 1, AUTH_NONE,
                     VERSION, CMD_CONNECT, RESERVED, ATYP_IPV4,
                     1, 2, 3, 4, 0, 5],
@@ -309,7 +819,7 @@ mod tests {
 
         let mut reactor = Core::new().unwrap();
         let address = "[::ffff:192.168.0.1]:80".to_addr().unwrap();
-        let stream = reactor.run(connect_stream(stream, address, Auth::None)).unwrap();
+        let stream = reactor.run(connect_stream(stream, address, vec![Auth::None])).unwrap();
 
         assert_eq!([VERSION, 1, AUTH_NONE,
                     VERSION, CMD_CONNECT, RESERVED, ATYP_IPV6,
@@ -333,8 +843,9 @@ mod tests {
 
         let mut reactor = Core::new().unwrap();
         let address = "z.com:80".to_addr().unwrap();
-        let stream = reactor.run(connect_stream(stream, address, Auth::None)).unwrap();
+        let stream = reactor.run(connect_stream(stream, address, vec![Auth::None])).unwrap();
 
+        assert_eq!("a.com:64000".to_addr().unwrap(), *stream.bound_addr());
         assert_eq!([VERSION, 1, AUTH_NONE,
                     VERSION, CMD_CONNECT, RESERVED, ATYP_DOMAIN_NAME,
                     5, b'z', b'.', b'c', b'o', b'm',
@@ -343,6 +854,180 @@ mod tests {
         assert!(stream.read_all());
     }
 
+    #[test]
+    fn bind_ipv4() {
+        let stream = Stream::new(&[
+            VERSION, AUTH_NONE,
+            VERSION, REP_SUCCEEDED, RESERVED, ATYP_IPV4,
+            192, 168, 1, 2,
+            8, 1,
+            VERSION, REP_SUCCEEDED, RESERVED, ATYP_IPV4,
+            10, 0, 0, 1,
+            0, 21,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "10.0.0.1:0".to_addr().unwrap();
+        let (bound, accept) = reactor.run(bind_stream(stream, address, vec![Auth::None])).unwrap();
+        assert_eq!("192.168.1.2:2049".to_addr().unwrap(), bound);
+
+        let (peer, stream) = reactor.run(accept).unwrap();
+        assert_eq!("10.0.0.1:21".to_addr().unwrap(), peer);
+        assert_eq!([VERSION, 1, AUTH_NONE,
+                    VERSION, CMD_BIND, RESERVED, ATYP_IPV4,
+                    10, 0, 0, 1, 0, 0],
+                   stream.write_buffer());
+        assert!(stream.read_all());
+    }
+
+    #[test]
+    fn bind_unspecified_address() {
+        let (url, proxy) = proxy("", vec![
+            (3, vec![VERSION, AUTH_NONE]),
+            (10, vec![VERSION, REP_SUCCEEDED, RESERVED, ATYP_IPV4, 0, 0, 0, 0, 8, 1]),
+        ]);
+        let address: SocketAddr = url.parse().unwrap();
+
+        let mut reactor = Core::new().unwrap();
+        let handle = reactor.handle();
+        let (bound, _) = reactor.run(bind(&address, "10.0.0.1:0", vec![Auth::None], &handle)).unwrap();
+        assert_eq!("127.0.0.1:2049".to_addr().unwrap(), bound);
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn associate_unspecified_relay() {
+        let stream = Stream::new(&[
+            VERSION, AUTH_NONE,
+            VERSION, REP_SUCCEEDED, RESERVED, ATYP_IPV4,
+            0, 0, 0, 0,
+            4, 0,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let local = "127.0.0.1:0".parse().unwrap();
+        let socket = UdpSocket::bind(&local, &reactor.handle()).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let proxy = "10.0.0.1".parse().unwrap();
+        let association = reactor.run(associate_stream(stream, socket, proxy, vec![Auth::None])).unwrap();
+
+        assert_eq!("10.0.0.1:1024".parse::<SocketAddr>().unwrap(), *association.relay_addr());
+        assert_eq!([VERSION, 1, AUTH_NONE,
+                    VERSION, CMD_UDP_ASSOCIATE, RESERVED, ATYP_IPV4,
+                    127, 0, 0, 1, (port >> 8) as u8, port as u8],
+                   association.control().write_buffer());
+        assert!(association.control().read_all());
+    }
+
+    #[test]
+    fn encapsulate_domain() {
+        let address = "a.com:53".to_addr().unwrap();
+        let datagram = encapsulate(&address, &[1, 2, 3]).unwrap();
+        assert_eq!([RESERVED, RESERVED, 0, ATYP_DOMAIN_NAME,
+                    5, b'a', b'.', b'c', b'o', b'm',
+                    0, 53,
+                    1, 2, 3],
+                   &datagram[..]);
+    }
+
+    #[test]
+    fn decapsulate_ipv4() {
+        let datagram = [RESERVED, RESERVED, 0, ATYP_IPV4,
+                        8, 8, 4, 4,
+                        0, 53,
+                        4, 5, 6];
+        let (address, data) = decapsulate(&datagram).unwrap().unwrap();
+        assert_eq!("8.8.4.4:53".to_addr().unwrap(), address);
+        assert_eq!([4, 5, 6], data);
+    }
+
+    #[test]
+    fn decapsulate_fragmented() {
+        let datagram = [RESERVED, RESERVED, 1, ATYP_IPV4,
+                        8, 8, 4, 4,
+                        0, 53];
+        assert!(decapsulate(&datagram).unwrap().is_none());
+    }
+
+    #[test]
+    fn decapsulate_truncated() {
+        let datagram = [RESERVED, RESERVED, 0, ATYP_DOMAIN_NAME, 5, b'a', b'.'];
+        assert!(decapsulate(&datagram).is_err());
+    }
+
+    #[test]
+    fn connect_refused() {
+        let stream = Stream::new(&[
+            VERSION, AUTH_NONE,
+            VERSION, 5, RESERVED, ATYP_IPV4,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "1.2.3.4:5".to_addr().unwrap();
+        let error = reactor.run(connect_stream(stream, address, vec![Auth::None])).err().unwrap();
+
+        assert_eq!(ErrorKind::ConnectionRefused, error.kind());
+        match Error::from_io(&error) {
+            Some(&Error::ConnectionRefused) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connect_truncated_greeting() {
+        let stream = Stream::new(&[VERSION]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "1.2.3.4:5".to_addr().unwrap();
+        let error = reactor.run(connect_stream(stream, address, vec![Auth::None])).err().unwrap();
+
+        assert_eq!(ErrorKind::UnexpectedEof, error.kind());
+        assert_eq!(Phase::Greeting, Error::from_io(&error).unwrap().phase());
+    }
+
+    #[test]
+    fn resolve_domain() {
+        let stream = Shared::new(&[
+            VERSION, AUTH_NONE,
+            VERSION, REP_SUCCEEDED, RESERVED, ATYP_IPV4,
+            93, 184, 216, 34,
+            0, 0,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let ip = reactor.run(resolve_stream(stream.clone(), "a.com", Auth::None)).unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)), ip);
+
+        let stream = stream.lock();
+        assert_eq!([VERSION, 1, AUTH_NONE,
+                    VERSION, CMD_RESOLVE, RESERVED, ATYP_DOMAIN_NAME,
+                    5, b'a', b'.', b'c', b'o', b'm', 0, 0],
+                   stream.write_buffer());
+        assert!(stream.read_all());
+    }
+
+    #[test]
+    fn resolve_ptr_ipv4() {
+        let stream = Shared::new(&[
+            VERSION, AUTH_NONE,
+            VERSION, REP_SUCCEEDED, RESERVED, ATYP_DOMAIN_NAME,
+            5, b'a', b'.', b'c', b'o', b'm',
+            0, 0,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34));
+        let domain = reactor.run(resolve_ptr_stream(stream.clone(), ip, Auth::None)).unwrap();
+        assert_eq!("a.com", domain);
+
+        let stream = stream.lock();
+        assert_eq!([VERSION, 1, AUTH_NONE,
+                    VERSION, CMD_RESOLVE_PTR, RESERVED, ATYP_IPV4,
+                    93, 184, 216, 34, 0, 0],
+                   stream.write_buffer());
+        assert!(stream.read_all());
+    }
+
     #[test]
     fn connect_auth_not_acceptable() {
         let stream = Stream::new(&[
@@ -351,7 +1036,7 @@ mod tests {
 
         let mut reactor = Core::new().unwrap();
         let address = "a.com:80".to_addr().unwrap();
-        let error = reactor.run(connect_stream(stream, address, Auth::None)).err().unwrap();
+        let error = reactor.run(connect_stream(stream, address, vec![Auth::None])).err().unwrap();
         assert_eq!("proxy: No acceptable authentication methods", format!("{}", error));
     }
 
@@ -365,7 +1050,7 @@ mod tests {
 
         let mut reactor = Core::new().unwrap();
         let address = "8.8.8.8:20".to_addr().unwrap();
-        let auth = Auth::UserPass("root".to_owned(), "secret".to_owned());
+        let auth = vec![Auth::UserPass(b"root".to_vec(), b"secret".to_vec())];
         let stream = reactor.run(connect_stream(stream, address, auth)).unwrap();
 
         assert!(stream.read_all());
@@ -379,6 +1064,120 @@ mod tests {
                    stream.write_buffer());
     }
 
+    #[test]
+    fn connect_auth_multiple_methods() {
+        let stream = Stream::new(&[
+            VERSION, AUTH_USER_PASS,
+            AUTH_USER_PASS_VERSION, AUTH_SUCCEEDED,
+            VERSION, REP_SUCCEEDED, RESERVED, ATYP_IPV4, 1, 2, 3, 4, 0, 80
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "8.8.8.8:20".to_addr().unwrap();
+        let auth = vec![Auth::None, Auth::UserPass(b"a".to_vec(), b"b".to_vec())];
+        let stream = reactor.run(connect_stream(stream, address, auth)).unwrap();
+
+        assert!(stream.read_all());
+        assert_eq!(Some(AUTH_USER_PASS), stream.auth_method());
+        assert_eq!([VERSION, 2, AUTH_NONE, AUTH_USER_PASS,
+                    AUTH_USER_PASS_VERSION,
+                    1, b'a',
+                    1, b'b',
+                    VERSION, CMD_CONNECT, RESERVED, ATYP_IPV4,
+                    8, 8, 8, 8,
+                    0, 20],
+                   stream.write_buffer());
+    }
+
+    #[test]
+    fn connect_auth_not_offered() {
+        let stream = Stream::new(&[
+            VERSION, AUTH_USER_PASS,
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "a.com:80".to_addr().unwrap();
+        let error = reactor.run(connect_stream(stream, address, vec![Auth::None])).err().unwrap();
+        assert_eq!("proxy: Server selected an invalid authentication method", format!("{}", error));
+    }
+
+    /// Authenticator using a private method that exchanges a single byte.
+    struct Token(u8);
+
+    impl Authenticator for Token {
+        fn methods(&self) -> Vec<u8> {
+            vec![0x80]
+        }
+
+        fn authenticate<S>(&self, _: u8, stream: S) -> IoFuture<S>
+            where S: Read + Write + Send + 'static
+        {
+            write_all(stream, vec![self.0]).and_then(|(stream, buff)| {
+                read_exact(stream, buff)
+            }).and_then(|(stream, buff)| {
+                if buff[0] == 0 {
+                    Ok(stream)
+                } else {
+                    Err(other("proxy: Token rejected"))
+                }
+            }).boxed()
+        }
+    }
+
+    #[test]
+    fn connect_auth_custom() {
+        let stream = Stream::new(&[
+            VERSION, 0x80,
+            0,
+            VERSION, REP_SUCCEEDED, RESERVED, ATYP_IPV4, 1, 2, 3, 4, 0, 80
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "8.8.8.8:20".to_addr().unwrap();
+        let auth = (Token(42), Auth::None);
+        let stream = reactor.run(connect_stream(stream, address, auth)).unwrap();
+
+        assert!(stream.read_all());
+        assert_eq!(Some(0x80), stream.auth_method());
+        assert_eq!([VERSION, 2, 0x80, AUTH_NONE,
+                    42,
+                    VERSION, CMD_CONNECT, RESERVED, ATYP_IPV4,
+                    8, 8, 8, 8,
+                    0, 20],
+                   stream.write_buffer());
+    }
+
+    #[test]
+    fn connect_isolation_key() {
+        let stream = Stream::new(&[
+            VERSION, AUTH_USER_PASS,
+            AUTH_USER_PASS_VERSION, AUTH_SUCCEEDED,
+            VERSION, REP_SUCCEEDED, RESERVED, ATYP_IPV4, 1, 2, 3, 4, 0, 80
+        ]);
+
+        let mut reactor = Core::new().unwrap();
+        let address = "8.8.8.8:20".to_addr().unwrap();
+        let auth = Isolation::Key("k".to_owned());
+        let stream = reactor.run(connect_stream(stream, address, auth)).unwrap();
+
+        assert!(stream.read_all());
+        assert_eq!([VERSION, 1, AUTH_USER_PASS,
+                    AUTH_USER_PASS_VERSION,
+                    1, b'k',
+                    1, b'k',
+                    VERSION, CMD_CONNECT, RESERVED, ATYP_IPV4,
+                    8, 8, 8, 8,
+                    0, 20],
+                   stream.write_buffer());
+    }
+
+    #[test]
+    fn isolation_random() {
+        let auth = Isolation::Random;
+        assert_eq!(vec![AUTH_USER_PASS], auth.methods());
+        assert!(auth.credentials() != auth.credentials());
+    }
+
     #[test]
     fn connect_auth_failed() {
         let stream = Stream::new(&[
@@ -389,7 +1188,7 @@ mod tests {
 
         let mut reactor = Core::new().unwrap();
         let address = "a.com:80".to_addr().unwrap();
-        let auth = Auth::UserPass("root".to_owned(), "secret".to_owned());
+        let auth = vec![Auth::UserPass(b"root".to_vec(), b"secret".to_vec())];
         let error = reactor.run(connect_stream(stream, address, auth)).err().unwrap();
 
         assert_eq!("proxy: Authentication failure",